@@ -0,0 +1,193 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Why a command line couldn't be split into arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizeError {
+    UnterminatedQuote,
+    TrailingBackslash,
+    /// `>` or `>>` without a file name after it
+    MissingRedirectTarget,
+    /// A redirection on a command whose output goes on down the pipeline
+    RedirectNotLast,
+}
+
+impl fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenizeError::UnterminatedQuote => write!(f, "unterminated quote"),
+            TokenizeError::TrailingBackslash => write!(f, "nothing to escape after the backslash"),
+            TokenizeError::MissingRedirectTarget => write!(f, "no file name after the redirection"),
+            TokenizeError::RedirectNotLast => write!(f, "only the last command of a pipeline can redirect its output"),
+        }
+    }
+}
+
+/// A command line split by `pipeline`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    /// Arguments of each command, the name first
+    pub stages: Vec<Vec<String>>,
+    /// Where the output of the last command goes instead of the screen
+    pub redirect: Option<Redirect>,
+}
+
+/// `> file` or `>> file` at the end of a pipeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub file: String,
+    /// `>>`: add to the end of the file instead of replacing it
+    pub append: bool,
+}
+
+/// Whether `c` may be part of a variable name
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Ends the argument in `token`. After a `>` or `>>`, which left `target` set to
+/// whether it appends, it's the file to redirect to instead.
+fn end_token(token: &mut String, tokens: &mut Vec<String>, target: &mut Option<bool>, redirect: &mut Option<Redirect>) {
+    let token = core::mem::take(token);
+    match target.take() {
+        Some(append) => *redirect = Some(Redirect { file: token, append }),
+        None => tokens.push(token),
+    }
+}
+
+/// Splits a command line into the commands of a pipeline, at each `|` outside quotes,
+/// and each command into arguments at whitespace. Double quotes group words into one
+/// argument (`""` is an empty one) and are removed; a backslash takes the next
+/// character literally, inside or outside quotes. `$NAME`, inside or outside quotes, is
+/// replaced by the value of `NAME` in `vars`, or by nothing if there is none. A `>` or
+/// `>>` outside quotes takes the argument after it out of the command as the file to
+/// redirect to. An empty command line gives a single command with no arguments.
+pub fn pipeline(input: &str, vars: &BTreeMap<String, String>) -> Result<Pipeline, TokenizeError> {
+    let mut stages = Vec::new();
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    // whether `token` has been started, so that `""` still counts
+    let mut in_token = false;
+    let mut quoted = false;
+    let mut redirect = None;
+    // set by `>` (false) or `>>` (true) until the file name after it
+    let mut target = None;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '$' if chars.peek().is_some_and(|&c| is_name_char(c)) => {
+                let mut name = String::new();
+                while let Some(c) = chars.next_if(|&c| is_name_char(c)) {
+                    name.push(c);
+                }
+                // an unset or empty variable doesn't start an argument, like in sh
+                if let Some(value) = vars.get(&name).filter(|value| !value.is_empty()) {
+                    token.push_str(value);
+                    in_token = true;
+                }
+            }
+            '\\' => {
+                token.push(chars.next().ok_or(TokenizeError::TrailingBackslash)?);
+                in_token = true;
+            }
+            '"' => {
+                quoted = !quoted;
+                in_token = true;
+            }
+            '|' if !quoted => {
+                if in_token {
+                    end_token(&mut token, &mut tokens, &mut target, &mut redirect);
+                    in_token = false;
+                }
+                if target.is_some() {
+                    return Err(TokenizeError::MissingRedirectTarget);
+                }
+                if redirect.is_some() {
+                    return Err(TokenizeError::RedirectNotLast);
+                }
+                stages.push(core::mem::take(&mut tokens));
+            }
+            '>' if !quoted => {
+                if in_token {
+                    end_token(&mut token, &mut tokens, &mut target, &mut redirect);
+                    in_token = false;
+                }
+                if target.is_some() {
+                    return Err(TokenizeError::MissingRedirectTarget);
+                }
+                target = Some(chars.next_if_eq(&'>').is_some());
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_token {
+                    end_token(&mut token, &mut tokens, &mut target, &mut redirect);
+                    in_token = false;
+                }
+            }
+            c => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(TokenizeError::UnterminatedQuote);
+    }
+    if in_token {
+        end_token(&mut token, &mut tokens, &mut target, &mut redirect);
+    }
+    if target.is_some() {
+        return Err(TokenizeError::MissingRedirectTarget);
+    }
+    stages.push(tokens);
+    Ok(Pipeline { stages, redirect })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> BTreeMap<String, String> {
+        BTreeMap::from([(String::from("NAME"), String::from("lab os")), (String::from("EMPTY"), String::new())])
+    }
+
+    /// Arguments of the last command of `input`
+    fn args(input: &str) -> Result<Vec<String>, TokenizeError> {
+        pipeline(input, &vars()).map(|mut pipeline| pipeline.stages.pop().unwrap())
+    }
+
+    #[test]
+    fn quotes_group_words() {
+        assert_eq!(args(r#"echo  "a  b" c"#).unwrap(), ["echo", "a  b", "c"]);
+        assert_eq!(args(r#"echo "" x"y z""#).unwrap(), ["echo", "", "xy z"]);
+        assert_eq!(args(r#"echo "a | b > c""#).unwrap(), ["echo", "a | b > c"]);
+        assert_eq!(args(r#"echo "open"#), Err(TokenizeError::UnterminatedQuote));
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        assert_eq!(args(r#"echo a\ b \"q\" "\"""#).unwrap(), ["echo", "a b", "\"q\"", "\""]);
+        assert_eq!(args(r"echo \| \> \$NAME").unwrap(), ["echo", "|", ">", "$NAME"]);
+        assert_eq!(args(r"echo a\"), Err(TokenizeError::TrailingBackslash));
+    }
+
+    #[test]
+    fn variables_expand() {
+        // the value stays one argument, quoted or not
+        assert_eq!(args("echo $NAME").unwrap(), ["echo", "lab os"]);
+        assert_eq!(args(r#"echo "[$NAME]" x$NAME"#).unwrap(), ["echo", "[lab os]", "xlab os"]);
+        // unset and empty ones are dropped, unless quoted
+        assert_eq!(args(r#"echo $EMPTY $UNSET "$EMPTY" $"#).unwrap(), ["echo", "", "$"]);
+    }
+
+    #[test]
+    fn pipes_and_redirects() {
+        let split = pipeline("ls | cat>>log", &vars()).unwrap();
+        assert_eq!(split.stages, [vec!["ls"], vec!["cat"]]);
+        assert_eq!(split.redirect, Some(Redirect { file: String::from("log"), append: true }));
+        assert_eq!(pipeline("", &vars()).unwrap().stages, [Vec::<String>::new()]);
+        assert_eq!(pipeline("echo >", &vars()), Err(TokenizeError::MissingRedirectTarget));
+        assert_eq!(pipeline("echo > a | cat", &vars()), Err(TokenizeError::RedirectNotLast));
+    }
+}
@@ -0,0 +1,165 @@
+use core::fmt;
+use core::iter::Peekable;
+use core::str::CharIndices;
+
+/// Why an expression couldn't be evaluated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalcError {
+    /// A character that can't start a number or an operator, at this byte offset
+    UnexpectedChar(char, usize),
+    /// The expression stops where a number or `(` should follow
+    UnexpectedEnd,
+    /// A `(` without its `)`
+    MissingParen,
+    DivisionByZero,
+    /// A number or a result doesn't fit in 64 signed bits
+    Overflow,
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CalcError::UnexpectedChar(c, at) => write!(f, "unexpected '{}' at position {}", c, at + 1),
+            CalcError::UnexpectedEnd => write!(f, "expression ends too early"),
+            CalcError::MissingParen => write!(f, "missing ')'"),
+            CalcError::DivisionByZero => write!(f, "division by zero"),
+            CalcError::Overflow => write!(f, "result doesn't fit in 64 bits"),
+        }
+    }
+}
+
+/// Evaluates an integer expression with `+ - * / %`, parentheses and unary minus.
+/// `*`, `/` and `%` bind tighter than `+` and `-`, and all of them are left
+/// associative; division rounds towards zero.
+pub fn evaluate(text: &str) -> Result<i64, CalcError> {
+    let mut parser = Parser { chars: text.char_indices().peekable() };
+    let value = parser.expression()?;
+    match parser.next() {
+        None => Ok(value),
+        Some((at, c)) => Err(CalcError::UnexpectedChar(c, at)),
+    }
+}
+
+/// Recursive descent over the grammar
+///
+/// ```text
+/// expression = term { ("+" | "-") term }
+/// term       = factor { ("*" | "/" | "%") factor }
+/// factor     = "-" factor | "(" expression ")" | number
+/// ```
+struct Parser<'a> {
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl Parser<'_> {
+    /// Next character that isn't whitespace, without taking it
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        self.chars.peek().map(|&(_, c)| c)
+    }
+
+    fn next(&mut self) -> Option<(usize, char)> {
+        self.peek();
+        self.chars.next()
+    }
+
+    fn expression(&mut self) -> Result<i64, CalcError> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.next();
+            let rhs = self.term()?;
+            value = match op {
+                '+' => value.checked_add(rhs),
+                _ => value.checked_sub(rhs),
+            }.ok_or(CalcError::Overflow)?;
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self) -> Result<i64, CalcError> {
+        let mut value = self.factor()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.next();
+            let rhs = self.factor()?;
+            value = match op {
+                '*' => value.checked_mul(rhs).ok_or(CalcError::Overflow)?,
+                _ if rhs == 0 => return Err(CalcError::DivisionByZero),
+                // only i64::MIN / -1 overflows
+                '/' => value.checked_div(rhs).ok_or(CalcError::Overflow)?,
+                _ => value.checked_rem(rhs).ok_or(CalcError::Overflow)?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn factor(&mut self) -> Result<i64, CalcError> {
+        match self.next() {
+            Some((_, '-')) => self.factor()?.checked_neg().ok_or(CalcError::Overflow),
+            Some((_, '(')) => {
+                let value = self.expression()?;
+                match self.next() {
+                    Some((_, ')')) => Ok(value),
+                    Some((at, c)) => Err(CalcError::UnexpectedChar(c, at)),
+                    None => Err(CalcError::MissingParen),
+                }
+            }
+            Some((_, c)) if c.is_ascii_digit() => {
+                // literals are never negative (that is unary minus), so i64::MIN overflows
+                let mut value = i64::from(c as u8 - b'0');
+                while let Some((_, digit)) = self.chars.next_if(|(_, c)| c.is_ascii_digit()) {
+                    value = value.checked_mul(10)
+                        .and_then(|value| value.checked_add(i64::from(digit as u8 - b'0')))
+                        .ok_or(CalcError::Overflow)?;
+                }
+                Ok(value)
+            }
+            Some((at, c)) => Err(CalcError::UnexpectedChar(c, at)),
+            None => Err(CalcError::UnexpectedEnd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn precedence() {
+        assert_eq!(evaluate("2 + 3 * 4"), Ok(14));
+        assert_eq!(evaluate("2 * 3 + 4"), Ok(10));
+        assert_eq!(evaluate("(2 + 3) * 4"), Ok(20));
+        assert_eq!(evaluate("10 - 7 % 4"), Ok(7));
+        assert_eq!(evaluate("-2 * -3"), Ok(6));
+        assert_eq!(evaluate("--5"), Ok(5));
+        assert_eq!(evaluate("2 * (3 + (4 - 1))"), Ok(12));
+    }
+
+    #[test]
+    fn left_associative() {
+        assert_eq!(evaluate("10 - 3 - 2"), Ok(5));
+        assert_eq!(evaluate("100 / 10 / 5"), Ok(2));
+        assert_eq!(evaluate("17 % 10 % 4"), Ok(3));
+        assert_eq!(evaluate("8 / 4 * 2"), Ok(4));
+        assert_eq!(evaluate("1 - 2 + 3"), Ok(2));
+    }
+
+    #[test]
+    fn division_rounds_towards_zero() {
+        assert_eq!(evaluate("7 / 2"), Ok(3));
+        assert_eq!(evaluate("-7 / 2"), Ok(-3));
+        assert_eq!(evaluate("-7 % 2"), Ok(-1));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(evaluate("1 / 0"), Err(CalcError::DivisionByZero));
+        assert_eq!(evaluate("1 % (2 - 2)"), Err(CalcError::DivisionByZero));
+        assert_eq!(evaluate("(1 + 2"), Err(CalcError::MissingParen));
+        assert_eq!(evaluate("1 +"), Err(CalcError::UnexpectedEnd));
+        assert_eq!(evaluate("1 + x"), Err(CalcError::UnexpectedChar('x', 4)));
+        assert_eq!(evaluate("1 2"), Err(CalcError::UnexpectedChar('2', 2)));
+        assert_eq!(evaluate("9223372036854775807 + 1"), Err(CalcError::Overflow));
+        assert_eq!(evaluate("9223372036854775808"), Err(CalcError::Overflow));
+        assert_eq!(evaluate("(-9223372036854775807 - 1) / -1"), Err(CalcError::Overflow));
+    }
+}
@@ -0,0 +1,112 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+
+/// Why a number couldn't be converted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConvError {
+    /// Only bases 2, 8, 10 and 16 are supported
+    UnsupportedBase(u32),
+    Empty,
+    InvalidDigit(char),
+    /// The value doesn't fit in 64 bits
+    Overflow,
+}
+
+impl fmt::Display for ConvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConvError::UnsupportedBase(base) => write!(f, "base {} isn't one of 2, 8, 10, 16", base),
+            ConvError::Empty => write!(f, "no digits"),
+            ConvError::InvalidDigit(c) => write!(f, "'{}' isn't a digit in that base", c),
+            ConvError::Overflow => write!(f, "value doesn't fit in 64 bits"),
+        }
+    }
+}
+
+fn check_base(base: u32) -> Result<u32, ConvError> {
+    match base {
+        2 | 8 | 10 | 16 => Ok(base),
+        _ => Err(ConvError::UnsupportedBase(base)),
+    }
+}
+
+/// Parses `text` as an unsigned number in `base`. A `0b`, `0o` or `0x` prefix is
+/// allowed when it matches the base, and `_` can separate digits.
+pub fn parse(text: &str, base: u32) -> Result<u64, ConvError> {
+    let base = check_base(base)?;
+    let prefixes: &[&str] = match base {
+        2 => &["0b", "0B"],
+        8 => &["0o", "0O"],
+        16 => &["0x", "0X"],
+        _ => &[],
+    };
+    let digits = prefixes.iter().find_map(|p| text.strip_prefix(p)).unwrap_or(text);
+    let mut value: u64 = 0;
+    let mut any = false;
+    for c in digits.chars().filter(|&c| c != '_') {
+        let digit = c.to_digit(base).ok_or(ConvError::InvalidDigit(c))?;
+        value = value.checked_mul(base as u64)
+            .and_then(|v| v.checked_add(digit as u64))
+            .ok_or(ConvError::Overflow)?;
+        any = true;
+    }
+    if any { Ok(value) } else { Err(ConvError::Empty) }
+}
+
+/// Formats `value` in `base`, with the prefix `parse` accepts.
+pub fn format(value: u64, base: u32) -> Result<String, ConvError> {
+    Ok(match check_base(base)? {
+        2 => format!("{:#b}", value),
+        8 => format!("{:#o}", value),
+        16 => format!("{:#x}", value),
+        _ => format!("{}", value),
+    })
+}
+
+/// Converts `text` from base `from` to base `to`.
+pub fn convert(text: &str, from: u32, to: u32) -> Result<String, ConvError> {
+    check_base(to)?;
+    format(parse(text, from)?, to)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_match_the_base() {
+        assert_eq!(parse("0xff", 16), Ok(255));
+        assert_eq!(parse("0XFF", 16), Ok(255));
+        assert_eq!(parse("0b1010", 2), Ok(10));
+        assert_eq!(parse("0o17", 8), Ok(15));
+        assert_eq!(parse("1010", 2), Ok(10));
+        // a prefix of another base is just digits, and 'x' isn't one
+        assert_eq!(parse("0x10", 10), Err(ConvError::InvalidDigit('x')));
+        // in hex, "0b" is two digits
+        assert_eq!(parse("0b1", 16), Ok(0xb1));
+        assert_eq!(parse("0x", 16), Err(ConvError::Empty));
+        assert_eq!(parse("1_000", 10), Ok(1000));
+    }
+
+    #[test]
+    fn digits_out_of_range_are_rejected() {
+        assert_eq!(parse("102", 2), Err(ConvError::InvalidDigit('2')));
+        assert_eq!(parse("78", 8), Err(ConvError::InvalidDigit('8')));
+        assert_eq!(parse("12a", 10), Err(ConvError::InvalidDigit('a')));
+        assert_eq!(parse("fg", 16), Err(ConvError::InvalidDigit('g')));
+        assert_eq!(parse("-1", 10), Err(ConvError::InvalidDigit('-')));
+        assert_eq!(parse("", 10), Err(ConvError::Empty));
+    }
+
+    #[test]
+    fn overflow_and_bases() {
+        assert_eq!(parse("18446744073709551615", 10), Ok(u64::MAX));
+        assert_eq!(parse("18446744073709551616", 10), Err(ConvError::Overflow));
+        assert_eq!(parse("12", 3), Err(ConvError::UnsupportedBase(3)));
+        assert_eq!(convert("255", 10, 16).as_deref(), Ok("0xff"));
+        assert_eq!(convert("0xff", 16, 2).as_deref(), Ok("0b11111111"));
+        assert_eq!(convert("0b11", 2, 10).as_deref(), Ok("3"));
+        assert_eq!(convert("1", 10, 7), Err(ConvError::UnsupportedBase(7)));
+    }
+}
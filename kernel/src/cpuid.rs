@@ -0,0 +1,118 @@
+//! What the CPU says about itself through the CPUID instruction.
+
+use core::arch::x86_64::{CpuidResult, __cpuid};
+
+/// Runs CPUID for `leaf`. Leaves above `max_leaf` or `max_extended_leaf` return
+/// whatever the CPU makes of them, usually the highest leaf it has.
+pub fn cpuid(leaf: u32) -> CpuidResult {
+    // every x86_64 CPU has CPUID
+    unsafe { __cpuid(leaf) }
+}
+
+/// Highest basic leaf (below 0x8000_0000).
+pub fn max_leaf() -> u32 {
+    cpuid(0).eax
+}
+
+/// Highest extended leaf (0x8000_0000 and up).
+pub fn max_extended_leaf() -> u32 {
+    cpuid(0x8000_0000).eax
+}
+
+/// Vendor string from leaf 0, like `GenuineIntel` or `AuthenticAMD`.
+pub fn vendor() -> [u8; 12] {
+    let id = cpuid(0);
+    let mut vendor = [0; 12];
+    for (chunk, register) in vendor.chunks_exact_mut(4).zip([id.ebx, id.edx, id.ecx]) {
+        chunk.copy_from_slice(&register.to_le_bytes());
+    }
+    vendor
+}
+
+/// Family, model and stepping from leaf 1, with the extended fields folded in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+}
+
+/// Decodes the processor signature in leaf 1's EAX.
+pub fn signature() -> Signature {
+    let eax = cpuid(1).eax;
+    let base_family = (eax >> 8) & 0xf;
+    let family = match base_family {
+        0xf => base_family + ((eax >> 20) & 0xff),
+        _ => base_family,
+    };
+    let model = match base_family {
+        0x6 | 0xf => ((eax >> 4) & 0xf) | ((eax >> 12) & 0xf0),
+        _ => (eax >> 4) & 0xf,
+    };
+    Signature { family, model, stepping: eax & 0xf }
+}
+
+/// Brand string from leaves 0x8000_0002 to 0x8000_0004, padded with spaces and NULs,
+/// or `None` if the CPU doesn't have those leaves.
+pub fn brand() -> Option<[u8; 48]> {
+    if max_extended_leaf() < 0x8000_0004 {
+        return None;
+    }
+    let mut brand = [0; 48];
+    for (chunk, leaf) in brand.chunks_exact_mut(16).zip(0x8000_0002..) {
+        let id = cpuid(leaf);
+        for (bytes, register) in chunk.chunks_exact_mut(4).zip([id.eax, id.ebx, id.ecx, id.edx]) {
+            bytes.copy_from_slice(&register.to_le_bytes());
+        }
+    }
+    Some(brand)
+}
+
+/// A CPU feature with a CPUID flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Apic,
+    X2Apic,
+    Sse,
+    /// The TSC runs at a constant rate in every power state
+    InvariantTsc,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 4] = [Feature::Apic, Feature::X2Apic, Feature::Sse, Feature::InvariantTsc];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Apic => "apic",
+            Feature::X2Apic => "x2apic",
+            Feature::Sse => "sse",
+            Feature::InvariantTsc => "invariant-tsc",
+        }
+    }
+}
+
+/// Whether the CPU has `feature`; false if the leaf with its flag is missing.
+pub fn has(feature: Feature) -> bool {
+    let (leaf, register, bit) = match feature {
+        Feature::Apic => (1, Register::Edx, 9),
+        Feature::X2Apic => (1, Register::Ecx, 21),
+        Feature::Sse => (1, Register::Edx, 25),
+        Feature::InvariantTsc => (0x8000_0007, Register::Edx, 8),
+    };
+    let max = if leaf >= 0x8000_0000 { max_extended_leaf() } else { max_leaf() };
+    if leaf > max {
+        return false;
+    }
+    let id = cpuid(leaf);
+    let value = match register {
+        Register::Ecx => id.ecx,
+        Register::Edx => id.edx,
+    };
+    value & (1 << bit) != 0
+}
+
+/// Result register a feature flag is in
+enum Register {
+    Ecx,
+    Edx,
+}
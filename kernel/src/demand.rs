@@ -0,0 +1,85 @@
+//! Demand paging: pages reserved here aren't mapped until they are first accessed,
+//! when the page fault handler maps a zeroed frame and the access is retried.
+
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageTableFlags, Translate};
+use kernel::paging::map_page;
+use crate::{frame_allocator, memory};
+
+/// Virtual addresses pages may be reserved in; nothing else is mapped there
+pub const LAZY_START: u64 = 0x0000_6000_0000_0000;
+pub const LAZY_SIZE: u64 = 1 << 30;
+
+/// Pages that may be reserved at once
+const MAX_LAZY: usize = 8;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Reserved pages, as address ranges
+static LAZY: Mutex<[Option<Range<u64>>; MAX_LAZY]> = Mutex::new([const { None }; MAX_LAZY]);
+
+/// Faults `handle_fault` resolved by mapping a page
+static FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Reserves the page containing `addr` for mapping on first access. Returns whether it
+/// wasn't reserved already.
+pub fn reserve(addr: u64) -> Result<bool, &'static str> {
+    if !(LAZY_START..LAZY_START + LAZY_SIZE).contains(&addr) {
+        return Err("address isn't in the demand paging range");
+    }
+    let page = addr & !(PAGE_SIZE - 1);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut lazy = LAZY.lock();
+        if lazy.iter().flatten().any(|range| range.contains(&page)) {
+            return Ok(false);
+        }
+        let slot = lazy.iter_mut().find(|slot| slot.is_none()).ok_or("too many reserved pages")?;
+        *slot = Some(page..page + PAGE_SIZE);
+        Ok(true)
+    })
+}
+
+/// Whether the page containing `addr` is mapped yet.
+pub fn is_mapped(addr: u64) -> bool {
+    frame_allocator::with_mapper(|mapper, _| {
+        matches!(mapper.translate(VirtAddr::new(addr)), TranslateResult::Mapped { .. })
+    })
+}
+
+/// Returns how many faults were resolved by mapping a reserved page.
+pub fn faults() -> usize {
+    FAULTS.load(Ordering::Relaxed)
+}
+
+/// Page fault handler for the `HandlerTable`: maps a zeroed frame at `addr` if it lies
+/// in a reserved page. Locks are only tried, as the fault may have come while they
+/// were held; then the fault is treated as a genuine one.
+pub fn handle_fault(addr: VirtAddr) -> bool {
+    let addr = addr.as_u64();
+    let reserved = LAZY.try_lock().is_some_and(|lazy| lazy.iter().flatten().any(|range| range.contains(&addr)));
+    if !reserved {
+        return false;
+    }
+    let mapped = frame_allocator::try_with_mapper(|mapper, frames| {
+        let Some(frame) = frames.allocate_frame() else { return false };
+        let phys = frame.start_address();
+        if let Some(page) = memory::phys_to_virt(phys.as_u64(), PAGE_SIZE) {
+            unsafe { core::ptr::write_bytes(page, 0, PAGE_SIZE as usize) };
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            if map_page(mapper, frames, VirtAddr::new(addr), phys, flags).is_ok() {
+                return true;
+            }
+        }
+        unsafe { frames.deallocate_frame(frame) };
+        false
+    });
+    let mapped = mapped.unwrap_or(false);
+    if mapped {
+        FAULTS.fetch_add(1, Ordering::Relaxed);
+    }
+    mapped
+}
@@ -1,37 +1,245 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Range;
 use bootloader_api::info::MemoryRegionKind::Usable;
 use bootloader_api::info::MemoryRegions;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::registers::control::Cr3;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB};
 use x86_64::{PhysAddr, VirtAddr};
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
+const FRAME_SIZE: u64 = 4096;
+
+static FRAMES: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+/// Sets up the global frame allocator over the usable regions of `memory_map`.
+pub fn init_frames(memory_map: &'static MemoryRegions) {
+    *FRAMES.lock() = Some(BitmapFrameAllocator::new(memory_map));
+}
+
+/// Runs `f` with the global frame allocator, with interrupts disabled so an interrupt
+/// handler can't deadlock on it.
+pub fn with_frames<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> R {
+    without_interrupts(|| f(FRAMES.lock().as_mut().expect("frame allocator not initialized")))
+}
+
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// Makes `mapper` the global page table mapper used by `with_mapper`.
+pub fn set_mapper(mapper: OffsetPageTable<'static>) {
+    *MAPPER.lock() = Some(mapper);
+}
+
+/// Runs `f` with the global mapper and frame allocator, with interrupts disabled.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R) -> R {
+    without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().expect("mapper not initialized");
+        f(mapper, FRAMES.lock().as_mut().expect("frame allocator not initialized"))
+    })
+}
+
+/// Like `with_mapper`, but gives `None` instead of spinning or panicking when the
+/// mapper or the frame allocator is in use or not set up yet. For the heap, which may
+/// need to grow while one of them is locked.
+pub fn try_with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R) -> Option<R> {
+    without_interrupts(|| {
+        let mut mapper = MAPPER.try_lock()?;
+        let mut frames = FRAMES.try_lock()?;
+        Some(f(mapper.as_mut()?, frames.as_mut()?))
+    })
+}
+
+/// Like `with_frames`, but gives `None` instead of spinning when the frame allocator is
+/// in use or not set up yet. For the timer interrupt, which may hit while it's locked.
+pub fn try_with_frames<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> Option<R> {
+    without_interrupts(|| Some(f(FRAMES.try_lock()?.as_mut()?)))
+}
+
+/// A usable region of physical memory, as a run of frames
+struct Span {
+    /// Frame number (physical address / 4 KiB) of the first frame
+    first: u64,
+    frames: usize,
+    /// Bit in the bitmap for the first frame
+    bit: usize,
+}
+
+/// Physical frame allocator keeping one bit per usable 4 KiB frame, set while the
+/// frame is in use. Allocating scans from the lowest word that may have a free frame,
+/// freeing is a bit flip.
+pub struct BitmapFrameAllocator {
+    spans: Vec<Span>,
+    bitmap: Vec<u64>,
+    used: usize,
+    total: usize,
+    /// No word before this one has a free frame
+    hint: usize,
 }
 
-impl BootInfoFrameAllocator {
+impl BitmapFrameAllocator {
     pub fn new(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
+        let mut spans = Vec::new();
+        let mut total = 0;
+        for region in memory_map.iter().filter(|region| region.kind == Usable) {
+            let first = region.start.div_ceil(FRAME_SIZE);
+            let end = region.end / FRAME_SIZE;
+            if end > first {
+                let frames = (end - first) as usize;
+                spans.push(Span { first, frames, bit: total });
+                total += frames;
+            }
+        }
+        let mut bitmap = vec![0u64; total.div_ceil(64)];
+        // the bits past the last frame don't stand for anything; keep them taken
+        if total % 64 != 0 {
+            bitmap[total / 64] = !0 << (total % 64);
+        }
+        BitmapFrameAllocator { spans, bitmap, used: 0, total, hint: 0 }
+    }
+
+    /// Number of frames currently handed out.
+    pub fn frames_used(&self) -> usize {
+        self.used
+    }
+
+    /// Number of usable frames in the memory map.
+    pub fn frames_total(&self) -> usize {
+        self.total
+    }
+
+    /// Marks the frames overlapping the physical range `range` as used, e.g. because
+    /// the heap lives there.
+    pub fn reserve(&mut self, range: Range<u64>) {
+        let first = range.start / FRAME_SIZE;
+        let end = range.end.div_ceil(FRAME_SIZE);
+        for frame in first..end {
+            if let Some(bit) = self.bit_of(frame) {
+                if !self.is_used(bit) {
+                    self.set(bit, true);
+                }
+            }
+        }
+    }
+
+    /// Marks the frames overlapping the physical range `range` as used, like `reserve`,
+    /// but only if all of them are usable and free; otherwise nothing changes. Give
+    /// them back with `free_contiguous`.
+    pub fn claim(&mut self, range: Range<u64>) -> Result<(), &'static str> {
+        let first = range.start / FRAME_SIZE;
+        let end = range.end.div_ceil(FRAME_SIZE);
+        for frame in first..end {
+            match self.bit_of(frame) {
+                None => return Err("not in a usable region"),
+                Some(bit) if self.is_used(bit) => return Err("in use"),
+                Some(_) => {}
+            }
+        }
+        for frame in first..end {
+            let bit = self.bit_of(frame).unwrap();
+            self.set(bit, true);
+        }
+        Ok(())
+    }
+
+    /// Allocates `count` physically contiguous frames and returns the first one.
+    #[allow(dead_code)]
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        if count == 0 {
+            return None;
         }
+        let (first, start) = self.spans.iter().find_map(|span| {
+            let mut run = 0;
+            for i in 0..span.frames {
+                run = if self.is_used(span.bit + i) { 0 } else { run + 1 };
+                if run == count {
+                    let start = i + 1 - count;
+                    return Some((span.first + start as u64, span.bit + start));
+                }
+            }
+            None
+        })?;
+        for bit in start..start + count {
+            self.set(bit, true);
+        }
+        Some(Self::frame(first))
+    }
+
+    /// Frees `count` contiguous frames starting at `frame`, as returned by
+    /// `allocate_contiguous`.
+    #[allow(dead_code)]
+    pub fn free_contiguous(&mut self, frame: PhysFrame, count: usize) {
+        let first = frame.start_address().as_u64() / FRAME_SIZE;
+        for number in first..first + count as u64 {
+            self.free(number);
+        }
+    }
+
+    /// Frees `frame` like `deallocate_frame`, but fails instead of corrupting the
+    /// bitmap if it lies outside the usable regions or isn't allocated.
+    pub fn try_free(&mut self, frame: PhysFrame) -> Result<(), &'static str> {
+        match self.bit_of(frame.start_address().as_u64() / FRAME_SIZE) {
+            None => Err("not in a usable region"),
+            Some(bit) if !self.is_used(bit) => Err("not allocated"),
+            Some(bit) => {
+                self.set(bit, false);
+                Ok(())
+            }
+        }
+    }
+
+    fn frame(number: u64) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(number * FRAME_SIZE))
+    }
+
+    fn bit_of(&self, number: u64) -> Option<usize> {
+        self.spans.iter()
+            .find(|span| span.first <= number && number < span.first + span.frames as u64)
+            .map(|span| span.bit + (number - span.first) as usize)
+    }
+
+    fn number_of(&self, bit: usize) -> u64 {
+        let span = self.spans.iter().rfind(|span| span.bit <= bit).expect("bit outside the bitmap");
+        span.first + (bit - span.bit) as u64
+    }
+
+    fn is_used(&self, bit: usize) -> bool {
+        self.bitmap[bit / 64] & (1 << (bit % 64)) != 0
     }
-    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
 
-        let usable_regions = regions.filter(|region| region.kind == Usable);
-        let address_ranges = usable_regions.map(|region| region.start..region.end);
-        let frame_addresses = address_ranges.flat_map(|region| region.step_by(4096));
+    fn set(&mut self, bit: usize, used: bool) {
+        if used {
+            self.bitmap[bit / 64] |= 1 << (bit % 64);
+            self.used += 1;
+        } else {
+            self.bitmap[bit / 64] &= !(1 << (bit % 64));
+            self.used -= 1;
+            self.hint = self.hint.min(bit / 64);
+        }
+    }
 
-        frame_addresses.map(|address| PhysFrame::containing_address(PhysAddr::new(address)))
+    fn free(&mut self, number: u64) {
+        match self.bit_of(number) {
+            Some(bit) if self.is_used(bit) => self.set(bit, false),
+            _ => debug_assert!(false, "freeing frame {:#x}, which isn't allocated", number * FRAME_SIZE),
+        }
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        let word = (self.hint..self.bitmap.len()).find(|&word| self.bitmap[word] != !0)?;
+        self.hint = word;
+        let bit = word * 64 + self.bitmap[word].trailing_ones() as usize;
+        self.set(bit, true);
+        Some(Self::frame(self.number_of(bit)))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free(frame.start_address().as_u64() / FRAME_SIZE);
     }
 }
 
@@ -48,4 +256,48 @@ fn active_level4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTab
     let page_table_pointer: *mut PageTable = virtual_address.as_mut_ptr();
 
     unsafe { &mut *page_table_pointer }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+
+    /// Frames over a memory map with the usable regions `usable`, in frame numbers
+    fn frames(usable: &[Range<u64>]) -> BitmapFrameAllocator {
+        let regions: Vec<MemoryRegion> = usable.iter()
+            .map(|frames| MemoryRegion { start: frames.start * FRAME_SIZE, end: frames.end * FRAME_SIZE, kind: MemoryRegionKind::Usable })
+            .collect();
+        BitmapFrameAllocator::new(Box::leak(Box::new(MemoryRegions::from(regions.leak()))))
+    }
+
+    fn number(frame: PhysFrame) -> u64 {
+        frame.start_address().as_u64() / FRAME_SIZE
+    }
+
+    #[test]
+    fn contiguous_runs() {
+        // 6 frames at 0x100, then 100 at 0x200
+        let mut frames = frames(&[0x100..0x106, 0x200..0x264]);
+        assert_eq!(frames.frames_total(), 106);
+        let a = frames.allocate_contiguous(4).unwrap();
+        assert_eq!(number(a), 0x100);
+        // doesn't fit in the 2 frames left of the first region
+        let b = frames.allocate_contiguous(4).unwrap();
+        assert_eq!(number(b), 0x200);
+        let c = frames.allocate_contiguous(16).unwrap();
+        assert_eq!(number(c), 0x204);
+        assert_eq!(frames.frames_used(), 24);
+
+        // the hole b leaves is reused, but only by runs that fit in it
+        frames.free_contiguous(b, 4);
+        assert_eq!(number(frames.allocate_contiguous(5).unwrap()), 0x214);
+        assert_eq!(number(frames.allocate_contiguous(3).unwrap()), 0x200);
+
+        frames.free_contiguous(a, 4);
+        frames.free_contiguous(c, 16);
+        assert_eq!(frames.frames_used(), 8);
+        assert!(frames.allocate_contiguous(101).is_none());
+        assert_eq!(number(frames.allocate_contiguous(6).unwrap()), 0x100);
+    }
+}
@@ -0,0 +1,113 @@
+//! Flat filesystem on the heap: named byte buffers, gone with the next reboot. The
+//! number of files and the bytes they hold are capped, so filling it up runs out of
+//! room here instead of exhausting the heap.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// Most files there may be at once
+pub const MAX_FILES: usize = 32;
+/// Most bytes all files may hold together
+pub const MAX_BYTES: usize = 64 * 1024;
+/// Longest file name, in bytes
+pub const MAX_NAME: usize = 32;
+
+/// Why a file operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    /// Empty, longer than `MAX_NAME`, or with characters other than letters, digits
+    /// and `._-`
+    BadName,
+    /// Already `MAX_FILES` files
+    TooManyFiles,
+    /// The files would hold more than `MAX_BYTES`
+    NoSpace,
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "no such file"),
+            FsError::BadName => write!(f, "names are 1 to {} letters, digits, '.', '_' or '-'", MAX_NAME),
+            FsError::TooManyFiles => write!(f, "too many files (at most {})", MAX_FILES),
+            FsError::NoSpace => write!(f, "not enough space (at most {} bytes in all)", MAX_BYTES),
+        }
+    }
+}
+
+struct Files {
+    files: BTreeMap<String, Vec<u8>>,
+    /// Bytes of all files together
+    bytes: usize,
+}
+
+static FILES: Mutex<Files> = Mutex::new(Files { files: BTreeMap::new(), bytes: 0 });
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+    if name.is_empty() || name.len() > MAX_NAME || !name.chars().all(valid) {
+        return Err(FsError::BadName);
+    }
+    Ok(())
+}
+
+/// Creates the file `name` holding `data`, or replaces what it holds.
+pub fn write(name: &str, data: &[u8]) -> Result<(), FsError> {
+    check_name(name)?;
+    let mut fs = FILES.lock();
+    let old = fs.files.get(name).map(Vec::len);
+    if old.is_none() && fs.files.len() == MAX_FILES {
+        return Err(FsError::TooManyFiles);
+    }
+    let bytes = fs.bytes - old.unwrap_or(0) + data.len();
+    if bytes > MAX_BYTES {
+        return Err(FsError::NoSpace);
+    }
+    fs.files.insert(String::from(name), Vec::from(data));
+    fs.bytes = bytes;
+    Ok(())
+}
+
+/// Adds `data` to the end of the file `name`, creating it if there is none. Leaves the
+/// file as it was if there's no room for all of `data`.
+pub fn append(name: &str, data: &[u8]) -> Result<(), FsError> {
+    check_name(name)?;
+    let mut fs = FILES.lock();
+    if !fs.files.contains_key(name) && fs.files.len() == MAX_FILES {
+        return Err(FsError::TooManyFiles);
+    }
+    let bytes = fs.bytes + data.len();
+    if bytes > MAX_BYTES {
+        return Err(FsError::NoSpace);
+    }
+    fs.files.entry(String::from(name)).or_default().extend_from_slice(data);
+    fs.bytes = bytes;
+    Ok(())
+}
+
+/// Returns a copy of what the file `name` holds.
+pub fn read(name: &str) -> Result<Vec<u8>, FsError> {
+    FILES.lock().files.get(name).cloned().ok_or(FsError::NotFound)
+}
+
+/// Deletes the file `name`.
+pub fn remove(name: &str) -> Result<(), FsError> {
+    let mut fs = FILES.lock();
+    let data = fs.files.remove(name).ok_or(FsError::NotFound)?;
+    fs.bytes -= data.len();
+    Ok(())
+}
+
+/// Names and sizes of all files, sorted by name.
+pub fn list() -> Vec<(String, usize)> {
+    FILES.lock().files.iter().map(|(name, data)| (name.clone(), data.len())).collect()
+}
+
+/// Bytes all files hold together.
+pub fn bytes_used() -> usize {
+    FILES.lock().bytes
+}
@@ -1,11 +1,12 @@
-use core::ptr::addr_of;
 use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::{Segment, CS, DS, ES, FS, GS, SS};
 use x86_64::instructions::tables::load_tss;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
-use x86_64::VirtAddr;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
+/// Interrupt stack table slot the double fault handler runs on, so it still has a
+/// working stack after a kernel stack overflow.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
 lazy_static! {
@@ -13,11 +14,20 @@ lazy_static! {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 4096 * 5;
-            static STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            // mutable so it ends up in writable memory
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
-            let stack_start = VirtAddr::from_ptr( addr_of!(STACK) );
+            let stack_start = VirtAddr::from_ptr( &raw const STACK );
             stack_start + STACK_SIZE as u64 // stack_end
         };
+        // stack the CPU switches to when an interrupt arrives in ring 3
+        tss.privilege_stack_table[0] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr( &raw const STACK );
+            stack_start + STACK_SIZE as u64
+        };
         tss
     };
 
@@ -26,6 +36,9 @@ lazy_static! {
 
         let code_selector = gdt.append(Descriptor::kernel_code_segment());
         let data_selector = gdt.append(Descriptor::kernel_data_segment());
+        // user data right before user code, the order `sysret` expects
+        let user_data_selector = gdt.append(Descriptor::user_data_segment());
+        let user_code_selector = gdt.append(Descriptor::user_code_segment());
         let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
 
         (
@@ -33,6 +46,8 @@ lazy_static! {
             Selectors {
                 code_selector,
                 data_selector,
+                user_code_selector: SegmentSelector::new(user_code_selector.index(), PrivilegeLevel::Ring3),
+                user_data_selector: SegmentSelector::new(user_data_selector.index(), PrivilegeLevel::Ring3),
                 tss_selector,
             },
         )
@@ -42,9 +57,21 @@ lazy_static! {
 struct Selectors {
     code_selector: SegmentSelector,
     data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// Returns the kernel (code, data) segment selectors.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// Returns the ring 3 (code, data) segment selectors.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
+
 pub fn init() {
     GDT.0.load();
     unsafe {
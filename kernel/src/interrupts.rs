@@ -1,15 +1,18 @@
 use core::fmt::Write;
 use core::ptr::NonNull;
-use crate::serial;
+use core::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use crate::{hlt_loop, keyboard, report, serial, usermode};
 use lazy_static::lazy_static;
 use spin::Mutex;
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::{PhysAddr, PrivilegeLevel, VirtAddr};
 use crate::HandlerTable;
+use crate::gdt::DOUBLE_FAULT_IST_INDEX;
+use crate::paging::map_page;
 use acpi::{AcpiHandler, AcpiTables, PhysicalMapping};
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use pc_keyboard::{DecodedKey, KeyCode};
 use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use x86_64::structures::paging::{FrameAllocator, Mapper, PhysFrame, Size4KiB};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
 use x86_64::instructions::port::Port;
 // This code is largely Copyright (c) 2019 Philipp Oppermann.
 // Gabriel Ferrer added:
@@ -35,6 +38,12 @@ impl LAPICAddress {
     }
 }
 
+impl Default for LAPICAddress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 lazy_static! {
     pub static ref LAPIC_ADDR: Mutex<LAPICAddress> = Mutex::new(LAPICAddress::new());
 }
@@ -165,10 +174,22 @@ lazy_static! {
 
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler)
+                .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        }
 
         idt[InterruptIndex::Timer as u8].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard as u8].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial as u8].set_handler_fn(serial_interrupt_handler);
+        idt[PIC_SERIAL_VECTOR].set_handler_fn(serial_interrupt_handler);
+        idt[PIC_SPURIOUS_VECTOR].set_handler_fn(pic_spurious_handler);
+        idt[SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
+        unsafe {
+            idt[usermode::USER_RETURN_VECTOR].set_handler_addr(usermode::user_return_addr())
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
 
         idt
     };
@@ -189,6 +210,11 @@ unsafe fn init_io_apic(
         ioapic_pointer
             .offset(4)
             .write_volatile(InterruptIndex::Keyboard as u8 as u32);
+        // IRQ 4 (COM1) goes through redirection entry 4, whose low half is register 0x18
+        ioapic_pointer.offset(0).write_volatile(0x18);
+        ioapic_pointer
+            .offset(4)
+            .write_volatile(InterruptIndex::Serial as u8 as u32);
     }
 }
 
@@ -205,13 +231,25 @@ unsafe fn init_local_apic(
         init_timer(lapic_pointer);
         init_keyboard(lapic_pointer);
     }
-    writeln!(serial(), "init LAPIC_ADDR {:?}", LAPIC_ADDR.lock()).unwrap();
+    crate::debug!("init LAPIC_ADDR {:?}", LAPIC_ADDR.lock());
+}
+
+/// Reads a LAPIC register through the mapping `init_apic` made; `None` if there is no
+/// LAPIC mapped, i.e. with the 8259 PIC.
+pub fn read_lapic(register: APICOffset) -> Option<u32> {
+    // interrupts off: `end_interrupt` takes the same lock
+    let lapic_pointer = x86_64::instructions::interrupts::without_interrupts(|| LAPIC_ADDR.lock().address);
+    if lapic_pointer.is_null() {
+        return None;
+    }
+    Some(unsafe { lapic_pointer.offset(register as isize / 4).read_volatile() })
 }
 
 unsafe fn init_timer(lapic_pointer: *mut u32) {
     unsafe {
         let svr = lapic_pointer.offset(APICOffset::Svr as isize / 4);
-        svr.write_volatile(svr.read_volatile() | 0x100); // Set bit 8
+        // bit 8 enables the LAPIC; the low byte is the vector of its spurious interrupts
+        svr.write_volatile((svr.read_volatile() & !0xFF) | 0x100 | u32::from(SPURIOUS_VECTOR));
 
         let lvt_lint1 = lapic_pointer.offset(APICOffset::LvtT as isize / 4);
         lvt_lint1.write_volatile(0x20 | (1 << 17)); // Vector 0x20, periodic mode
@@ -224,6 +262,98 @@ unsafe fn init_timer(lapic_pointer: *mut u32) {
     }
 }
 
+/// PIT input clock, in Hz
+pub(crate) const PIT_HZ: u64 = 1_193_182;
+/// Length of the calibration interval: 1/100 s
+pub(crate) const CALIBRATION_DIVISOR: u64 = 100;
+
+/// Reprograms the LAPIC timer to fire about `hz` times a second and returns the
+/// frequency actually achieved. The LAPIC timer's input clock isn't known, so it is
+/// first measured against PIT channel 2 (ports 0x42, 0x43 and 0x61). Without an APIC
+/// (after `init_pic`) PIT channel 0 is reprogrammed instead. Call this with interrupts
+/// disabled, after `init_apic` or `init_pic`.
+pub fn set_timer_hz(hz: u64) -> u64 {
+    let lapic_pointer = LAPIC_ADDR.lock().address;
+    if hz == 0 {
+        return timer_hz();
+    }
+    if backend() == InterruptBackend::Pic {
+        return set_pit_hz(hz);
+    }
+    let counts_per_sec = unsafe { measure_lapic_timer(lapic_pointer) } * CALIBRATION_DIVISOR;
+    if counts_per_sec == 0 {
+        crate::warn!("LAPIC timer calibration failed, keeping {} Hz", timer_hz());
+        return timer_hz();
+    }
+    let initial = (counts_per_sec / hz).clamp(1, u64::from(u32::MAX));
+    unsafe {
+        lapic_pointer.offset(APICOffset::LvtT as isize / 4).write_volatile(0x20 | (1 << 17)); // Vector 0x20, periodic mode
+        lapic_pointer.offset(APICOffset::Ticr as isize / 4).write_volatile(initial as u32);
+    }
+    let effective = (counts_per_sec / initial).max(1);
+    TIMER_HZ.store(effective, Ordering::Relaxed);
+    crate::info!("LAPIC timer: {} counts/s, initial count {}, {} Hz", counts_per_sec, initial, effective);
+    effective
+}
+
+/// Counts how far the LAPIC timer (divide by 16) runs down during 1/CALIBRATION_DIVISOR
+/// of a second. Leaves the LAPIC timer masked.
+unsafe fn measure_lapic_timer(lapic_pointer: *mut u32) -> u64 {
+    unsafe {
+        let lvt_timer = lapic_pointer.offset(APICOffset::LvtT as isize / 4);
+        lvt_timer.write_volatile(0x20 | (1 << 16)); // Vector 0x20, masked, one-shot
+        lapic_pointer.offset(APICOffset::Tdcr as isize / 4).write_volatile(0x3); // Divide by 16 mode
+        let tccr = lapic_pointer.offset(APICOffset::Tccr as isize / 4);
+        let ticr = lapic_pointer.offset(APICOffset::Ticr as isize / 4);
+        let mut remaining = u32::MAX;
+        pit_interval(|| ticr.write_volatile(u32::MAX), || remaining = tccr.read_volatile());
+        u64::from(u32::MAX - remaining)
+    }
+}
+
+/// Times 1/CALIBRATION_DIVISOR of a second with PIT channel 2 in one-shot mode (ports
+/// 0x42, 0x43 and 0x61), calling `start` as the count starts and `stop` as it runs out.
+/// Polls, so interrupts should be disabled.
+pub(crate) fn pit_interval(start: impl FnOnce(), stop: impl FnOnce()) {
+    let mut gate = Port::<u8>::new(0x61);
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel2 = Port::<u8>::new(0x42);
+    let count = (PIT_HZ / CALIBRATION_DIVISOR) as u16;
+    unsafe {
+        // gate low, speaker off; channel 2, lobyte/hibyte, mode 1 (hardware one-shot)
+        let control = gate.read() & !0b11;
+        gate.write(control);
+        command.write(0b1011_0010);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        // a rising gate starts the count, pulling OUT2 (bit 5) low until it runs out
+        gate.write(control | 1);
+        while gate.read() & 0x20 != 0 {}
+        start();
+        while gate.read() & 0x20 == 0 {}
+        stop();
+        gate.write(control);
+    }
+}
+
+/// Runs PIT channel 0, which the PIC delivers as IRQ0, as a rate generator at about
+/// `hz` (18 Hz at the least) and returns the frequency achieved.
+fn set_pit_hz(hz: u64) -> u64 {
+    let divisor = (PIT_HZ / hz).clamp(1, 0x1_0000);
+    unsafe {
+        // channel 0, lobyte/hibyte, mode 2 (rate generator); a count of 0 means 65536
+        Port::<u8>::new(0x43).write(0b0011_0100);
+        let mut channel0 = Port::<u8>::new(0x40);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+    let effective = (PIT_HZ / divisor).max(1);
+    TIMER_HZ.store(effective, Ordering::Relaxed);
+    crate::info!("PIT timer: divisor {}, {} Hz", divisor, effective);
+    effective
+}
+
 unsafe fn init_keyboard(lapic_pointer: *mut u32) {
     unsafe {
         let keyboard_register = lapic_pointer.offset(APICOffset::LvtLint1 as isize / 4);
@@ -236,26 +366,49 @@ fn map_apic(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> VirtAddr {
-    use x86_64::structures::paging::Page;
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let physical_address = PhysAddr::new(physical_address);
-    let page = Page::containing_address(VirtAddr::new(physical_address.as_u64()));
-    let frame = PhysFrame::containing_address(physical_address);
+    let virtual_address = VirtAddr::new(physical_address.align_down(4096u64).as_u64());
 
     let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE;
 
-    unsafe {
-        mapper
-            .map_to(page, frame, flags, frame_allocator)
-            .expect("APIC mapping failed")
-            .flush();
-    }
+    map_page(mapper, frame_allocator, virtual_address, physical_address, flags)
+        .expect("APIC mapping failed");
+
+    virtual_address
+}
 
-    page.start_address()
+/// Which interrupt controller delivers the timer, keyboard and serial IRQs. Chosen by
+/// calling `init_apic` or `init_pic`, then passed on to `HandlerTable::start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptBackend {
+    /// The local APIC's own timer and LINT1 (keyboard), and the IO APIC for COM1, with
+    /// the 8259s masked. Every IRQ is acknowledged by writing the local APIC's EOI
+    /// register; the IO APIC needs nothing.
+    Apic,
+    /// The 8259 pair, with the PIT on IRQ0, for machines or emulators without an APIC
+    /// (or ACPI tables to find it with). IRQs are acknowledged with a non-specific EOI
+    /// command to the master's port 0x20; IRQs 8-15 would need one to the slave (0xA0)
+    /// as well, but they stay masked.
+    Pic,
 }
 
-pub fn init_apic(rsdp: usize, offset: u64, mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> *mut u32 {
+/// Backend set up by `init_apic` or `init_pic`, as an `InterruptBackend` cast to `u8`.
+/// Atomic, as every IRQ reads it to send its EOI.
+static BACKEND: AtomicU8 = AtomicU8::new(InterruptBackend::Pic as u8);
+
+/// Returns the interrupt controller in use.
+pub fn backend() -> InterruptBackend {
+    match BACKEND.load(Ordering::Relaxed) {
+        b if b == InterruptBackend::Apic as u8 => InterruptBackend::Apic,
+        _ => InterruptBackend::Pic,
+    }
+}
+
+/// Sets up the IO APIC and the local APIC found through the ACPI tables at `rsdp`, or
+/// the 8259 PIC if the tables describe no APIC.
+pub fn init_apic(rsdp: usize, offset: u64, mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> InterruptBackend {
     let handler = AcpiHandlerImpl::new(VirtAddr::new(offset));
     let acpi_tables = unsafe { AcpiTables::from_rsdp(handler, rsdp).expect("Failed to parse ACPI tables") };
     let platform_info = acpi_tables.platform_info().expect("Failed to get platform info");
@@ -268,16 +421,46 @@ pub fn init_apic(rsdp: usize, offset: u64, mapper: &mut impl Mapper<Size4KiB>, f
             let local_apic_address = apic.local_apic_address;
             unsafe { init_local_apic(local_apic_address as usize, mapper, frame_allocator); }
         },
-        _ => {
-            // handler other interrupt models, if necessary
+        model => {
+            crate::info!("no APIC in the ACPI tables ({:?}), using the 8259 PIC", model);
+            return init_pic();
         }
     }
 
     disable_pic();
+    BACKEND.store(InterruptBackend::Apic as u8, Ordering::Relaxed);
 
-    writeln!(serial(), "APIC setup completed, pending interrupt and setup IDT.").unwrap();
-    writeln!(serial(), "LAPIC address: {:?}", LAPIC_ADDR.lock()).unwrap();
-    LAPIC_ADDR.lock().address
+    crate::info!("APIC setup completed, pending interrupt and setup IDT.");
+    crate::debug!("LAPIC address: {:?}", LAPIC_ADDR.lock());
+    InterruptBackend::Apic
+}
+
+/// Sets up the legacy 8259 PICs instead of the APIC, for firmware without ACPI tables:
+/// IRQ0 (the PIT) raises the timer vector, IRQ1 the keyboard's and IRQ4 (COM1)
+/// `PIC_SERIAL_VECTOR`. All lines stay masked until `init_idt` unmasks those three. The
+/// PIT starts at its slowest rate until `set_timer_hz`.
+pub fn init_pic() -> InterruptBackend {
+    let mut command1 = Port::<u8>::new(0x20);
+    let mut data1 = Port::<u8>::new(0x21);
+    let mut command2 = Port::<u8>::new(0xA0);
+    let mut data2 = Port::<u8>::new(0xA1);
+    unsafe {
+        // ICW1: initialize, ICW4 follows; ICW2: vector offset; ICW3: cascade on IRQ2;
+        // ICW4: 8086 mode
+        command1.write(0x11);
+        command2.write(0x11);
+        data1.write(PIC_1_OFFSET);
+        data2.write(PIC_2_OFFSET);
+        data1.write(1 << 2);
+        data2.write(2);
+        data1.write(0x01);
+        data2.write(0x01);
+    }
+    disable_pic();
+    BACKEND.store(InterruptBackend::Pic as u8, Ordering::Relaxed);
+    set_pit_hz(1);
+    crate::info!("8259 PIC set up, vectors {:#x} to {:#x}", PIC_1_OFFSET, PIC_2_OFFSET + 7);
+    InterruptBackend::Pic
 }
 
 fn disable_pic() {
@@ -290,76 +473,336 @@ fn disable_pic() {
 }
 
 fn end_interrupt() {
-    let binding = LAPIC_ADDR.lock();
-    unsafe { binding.address.offset(APICOffset::Eoi as isize / 4).write_volatile(0); }
+    match backend() {
+        InterruptBackend::Apic => {
+            let binding = LAPIC_ADDR.lock();
+            unsafe { binding.address.offset(APICOffset::Eoi as isize / 4).write_volatile(0); }
+        }
+        // only the master is ever unmasked: a non-specific EOI to it
+        InterruptBackend::Pic => unsafe { Port::<u8>::new(0x20).write(0x20) },
+    }
 }
 
-/// Initializes the interrupt table with the given interrupt handlers.
-pub fn init_idt(handlers: HandlerTable, lapic_pointer: *mut u32) {
-    LAPIC_ADDR.lock().address = lapic_pointer;
-    writeln!(serial(), "initialize IDT with LAPIC_ADDR {:?}", LAPIC_ADDR.lock()).unwrap();
+/// Loads the interrupt table so CPU exceptions are reported from here on. Hardware
+/// interrupts stay disabled until `init_idt`.
+pub fn load_idt() {
+    IDT.load();
+}
+
+/// Initializes the interrupt table with the given interrupt handlers and lets the IRQs
+/// of `backend`, which must be the one set up, through.
+pub fn init_idt(handlers: HandlerTable, backend: InterruptBackend) {
+    assert_eq!(backend, self::backend(), "starting with an interrupt backend that isn't set up");
+    crate::debug!("initialize IDT for the {:?} backend, LAPIC_ADDR {:?}", backend, LAPIC_ADDR.lock());
     *(HANDLERS.lock()) = Some(handlers);
+    if backend == InterruptBackend::Pic {
+        // unmask IRQ0, IRQ1 and IRQ4 only
+        unsafe { Port::<u8>::new(0x21).write(!0b0001_0011) };
+    }
 
     IDT.load();
     x86_64::instructions::interrupts::enable();
 }
 
+/// Not fatal: logs where the `int3` was and returns, so execution carries on after it.
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
-    writeln!(serial(), "EXCEPTION: BREAKPOINT\n{:#?}", stack_frame).unwrap();
+    // the saved rip already points past the one byte int3
+    writeln!(serial(), "EXCEPTION: BREAKPOINT at rip {:?}\n{:#?}", stack_frame.instruction_pointer - 1u64, stack_frame).ok();
 }
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-    panic!("EXCEPTION: PAGE FAULT access address: {:?}\n ErrorCode: {:?}\n{:#?}", Cr2::read(), error_code, stack_frame);
+    let address = Cr2::read();
+    // the page wasn't present: the handler may map it, and then the access is retried.
+    // The table may be locked by whatever faulted; then it's a genuine fault anyway.
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        let handlers = HANDLERS.try_lock().and_then(|handlers| *handlers);
+        if let (Ok(address), Some(handlers)) = (&address, handlers) {
+            if handlers.handle_page_fault(*address) {
+                return;
+            }
+        }
+    }
+    report(format_args!("EXCEPTION: PAGE FAULT access address: {:?}\n ErrorCode: {:?}\n", address, error_code));
+    writeln!(serial(), "{:#?}", stack_frame).ok();
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    // a non-zero error code is a segment selector error: bit 0 external, bits 1-2 the
+    // table (GDT, IDT, LDT, IDT), the rest the selector index
+    let table = match (error_code >> 1) & 0b11 {
+        0 => "GDT",
+        2 => "LDT",
+        _ => "IDT",
+    };
+    report(format_args!("EXCEPTION: GENERAL PROTECTION FAULT at rip {:?} cs {:#x}\n ErrorCode: {:#x} ({} index {}{})\n",
+        stack_frame.instruction_pointer, stack_frame.code_segment.0, error_code, table, error_code >> 3,
+        if error_code & 1 != 0 { ", external" } else { "" }));
+    writeln!(serial(), "{:#?}", stack_frame).ok();
+    hlt_loop();
 }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    report(format_args!("EXCEPTION: DOUBLE FAULT at {:?} (stack pointer {:?})\n",
+        stack_frame.instruction_pointer, stack_frame.stack_pointer));
+    writeln!(serial(), "{:#?}", stack_frame).ok();
+    hlt_loop();
 }
 
 const PIC_1_OFFSET: u8 = 0x20;
+const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+/// Vector of IRQ4 (COM1) with the 8259 PIC; the IO APIC sends it to `Serial` instead
+const PIC_SERIAL_VECTOR: u8 = PIC_1_OFFSET + 4;
+/// Vector of IRQ7 with the 8259 PIC, which is also where its spurious interrupts go
+const PIC_SPURIOUS_VECTOR: u8 = PIC_1_OFFSET + 7;
+/// Vector the LAPIC raises for spurious interrupts, set in its SVR
+const SPURIOUS_VECTOR: u8 = 0xFF;
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial,
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how many spurious interrupts the LAPIC or the 8259 PIC raised since startup.
+pub fn spurious_interrupts() -> u64 {
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+/// Timer interrupts per second; about 1 with the setup from `init_timer`, until
+/// `set_timer_hz` calibrates it.
+static TIMER_HZ: AtomicU64 = AtomicU64::new(1);
+
+/// Returns how many timer interrupts there are per second.
+pub fn timer_hz() -> u64 {
+    TIMER_HZ.load(Ordering::Relaxed)
+}
+
+/// Returns the number of timer interrupts since startup. The count wraps around, so
+/// compare two readings with `wrapping_sub`.
+pub fn current_ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Runs the table's `irq_hooks` around a hardware interrupt handler: the enter hook
+/// right away, the exit hook once it's dropped
+struct IrqEntry(Option<fn()>);
+
+impl IrqEntry {
+    fn new(handlers: Option<HandlerTable>) -> Self {
+        if let Some(enter) = handlers.and_then(|handlers| handlers.irq_enter) {
+            enter();
+        }
+        IrqEntry(handlers.and_then(|handlers| handlers.irq_exit))
+    }
+}
+
+impl Drop for IrqEntry {
+    fn drop(&mut self) {
+        if let Some(exit) = self.0 {
+            exit();
+        }
+    }
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    
-    let h = &*HANDLERS.lock();
+    // copy the table out so the handler doesn't run with it locked
+    let h = *HANDLERS.lock();
+    let _entry = IrqEntry::new(h);
+    let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    // acknowledge first: the handler may switch to another task and only come back
+    // here much later
+    end_interrupt();
+
     if let Some(handler) = h {
+        // a lone ESC over serial only turns into a key once nothing followed it
+        let escape = SERIAL_INPUT.lock().check_timeout(now);
+        if let Some(key) = escape {
+            handler.handle_keyboard(key);
+        }
         handler.handle_timer();
     }
+}
 
-    end_interrupt();
+/// The LAPIC doesn't count a spurious interrupt as in service, so it gets no EOI;
+/// one would acknowledge some other interrupt that is.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// IRQ7 is never unmasked, so this is normally a spurious interrupt: the 8259 raises one
+/// when a request goes away before it is acknowledged. Those aren't in service and get
+/// no EOI.
+extern "x86-interrupt" fn pic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    let mut command = Port::<u8>::new(0x20);
+    // OCW3: read the in-service register
+    let in_service = unsafe {
+        command.write(0x0B);
+        command.read()
+    };
+    if in_service & (1 << 7) != 0 {
+        end_interrupt();
+    } else {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let h = *HANDLERS.lock();
+    let _entry = IrqEntry::new(h);
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key,
-                HandleControl::Ignore)
-            );
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    // decode first, releasing the keyboard before calling the handler, which may
+    // want to look at the modifier state
+    let key = keyboard::decode(scancode);
+    // acknowledge before dispatching so a handler that waits for the timer can get it
+    end_interrupt();
+
+    if let (Some(key), Some(handler)) = (key, h) {
+        handler.handle_keyboard(key);
     }
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+/// Turns bytes received over serial into the keys the keyboard handler gets. Both
+/// `\r` and `\n` end a line (with `\r\n` counting once), DEL and BS are Backspace,
+/// and the VT100 sequences for the arrow, Home, End, Delete, Page Up/Down and F1-F4
+/// keys are understood. An ESC not completed into a sequence within
+/// `ESCAPE_TIMEOUT_MS` is a key of its own. Bytes outside ASCII are dropped.
+struct SerialDecoder {
+    after_cr: bool,
+    escape: SerialEscape,
+    param: u8,
+    /// Tick the ESC of the sequence in progress came at
+    escape_tick: u64,
+}
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            let h = &*HANDLERS.lock();
-            if let Some(handler) = h {
-                handler.handle_keyboard(key);
+/// How long an escape sequence may take to arrive before its ESC counts as a key
+const ESCAPE_TIMEOUT_MS: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SerialEscape {
+    None,
+    /// Got ESC
+    Start,
+    /// Inside `ESC [ ...`
+    Csi,
+    /// Got `ESC O`, which F1-F4 start with
+    Ss3,
+}
+
+impl SerialDecoder {
+    const fn new() -> Self {
+        Self { after_cr: false, escape: SerialEscape::None, param: 0, escape_tick: 0 }
+    }
+
+    /// Gives up on an escape sequence that hasn't been completed in time, returning the
+    /// ESC it started with as a key. Called from the timer interrupt.
+    fn check_timeout(&mut self, now: u64) -> Option<DecodedKey> {
+        let timeout = (ESCAPE_TIMEOUT_MS * timer_hz()).div_ceil(1000);
+        // a tick more, as the ESC may have come just before a tick
+        if self.escape == SerialEscape::None || now.wrapping_sub(self.escape_tick) <= timeout {
+            return None;
+        }
+        self.escape = SerialEscape::None;
+        Some(DecodedKey::Unicode('\u{1b}'))
+    }
+
+    fn decode(&mut self, byte: u8) -> Option<DecodedKey> {
+        let after_cr = core::mem::replace(&mut self.after_cr, byte == b'\r');
+        match (self.escape, byte) {
+            (SerialEscape::Start, b'[') => {
+                self.escape = SerialEscape::Csi;
+                self.param = 0;
+                None
+            }
+            (SerialEscape::Start, b'O') => {
+                self.escape = SerialEscape::Ss3;
+                None
+            }
+            (SerialEscape::Ss3, _) => {
+                self.escape = SerialEscape::None;
+                let code = match byte {
+                    b'P' => KeyCode::F1,
+                    b'Q' => KeyCode::F2,
+                    b'R' => KeyCode::F3,
+                    b'S' => KeyCode::F4,
+                    b'H' => KeyCode::Home,
+                    b'F' => KeyCode::End,
+                    _ => return None,
+                };
+                Some(DecodedKey::RawKey(code))
+            }
+            (SerialEscape::Start, _) => {
+                self.escape = SerialEscape::None;
+                None
+            }
+            (SerialEscape::Csi, b'0'..=b'9') => {
+                self.param = self.param.saturating_mul(10).saturating_add(byte - b'0');
+                None
+            }
+            (SerialEscape::Csi, 0x40..=0x7e) => {
+                self.escape = SerialEscape::None;
+                let code = match (byte, self.param) {
+                    (b'A', _) => KeyCode::ArrowUp,
+                    (b'B', _) => KeyCode::ArrowDown,
+                    (b'C', _) => KeyCode::ArrowRight,
+                    (b'D', _) => KeyCode::ArrowLeft,
+                    (b'H', _) | (b'~', 1 | 7) => KeyCode::Home,
+                    (b'F', _) | (b'~', 4 | 8) => KeyCode::End,
+                    (b'~', 3) => KeyCode::Delete,
+                    (b'~', 5) => KeyCode::PageUp,
+                    (b'~', 6) => KeyCode::PageDown,
+                    (b'~', 11) => KeyCode::F1,
+                    (b'~', 12) => KeyCode::F2,
+                    (b'~', 13) => KeyCode::F3,
+                    (b'~', 14) => KeyCode::F4,
+                    _ => return None,
+                };
+                Some(DecodedKey::RawKey(code))
             }
+            (SerialEscape::Csi, _) => None,
+            (SerialEscape::None, 0x1b) => {
+                self.escape = SerialEscape::Start;
+                self.escape_tick = current_ticks();
+                None
+            }
+            (SerialEscape::None, b'\n') if after_cr => None,
+            (SerialEscape::None, b'\r' | b'\n') => Some(DecodedKey::Unicode('\n')),
+            (SerialEscape::None, 0x7f | 0x08) => Some(DecodedKey::Unicode('\u{8}')),
+            (SerialEscape::None, 0..=0x7e) => Some(DecodedKey::Unicode(byte as char)),
+            (SerialEscape::None, _) => None,
         }
     }
+}
+
+static SERIAL_INPUT: Mutex<SerialDecoder> = Mutex::new(SerialDecoder::new());
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let h = *HANDLERS.lock();
+    let _entry = IrqEntry::new(h);
+    let mut line_status = Port::<u8>::new(0x3FD);
+    let mut data = Port::<u8>::new(0x3F8);
 
+    // acknowledge first, for the same reason as in the keyboard handler
     end_interrupt();
 
-}
\ No newline at end of file
+    // bit 0 of the line status register: a received byte is waiting
+    while unsafe { line_status.read() } & 1 != 0 {
+        let byte = unsafe { data.read() };
+        let key = SERIAL_INPUT.lock().decode(byte);
+        if let (Some(key), Some(handler)) = (key, h) {
+            handler.handle_keyboard(key);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use pc_keyboard::layouts::{AnyLayout, De105Key, Us104Key};
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, Keyboard, Modifiers, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// The decoder assumes scancode set 1, which the PS/2 controller translates to by
+// default (and which USB legacy emulation provides).
+static KEYBOARD: Mutex<Keyboard<AnyLayout, ScancodeSet1>> = Mutex::new(new_keyboard(Layout::Us));
+
+/// Keyboard layouts `set_layout` can switch between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// US 104-key
+    Us,
+    /// German 105-key
+    De,
+}
+
+const fn new_keyboard(layout: Layout) -> Keyboard<AnyLayout, ScancodeSet1> {
+    let layout = match layout {
+        Layout::Us => AnyLayout::Us104Key(Us104Key),
+        Layout::De => AnyLayout::De105Key(De105Key),
+    };
+    // Ctrl+letter decodes to the matching control character (Ctrl+C is '\u{3}'),
+    // the same as a terminal sends over serial
+    Keyboard::new(ScancodeSet1::new(), layout, HandleControl::MapLettersToUnicode)
+}
+
+/// Switches the layout scancodes are decoded with. Takes effect from the next key
+/// press; modifier and lock key state start over.
+pub fn set_layout(layout: Layout) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        *keyboard = new_keyboard(layout);
+        set_leds(keyboard.get_modifiers());
+    });
+}
+
+/// Returns the modifier keys (Shift, Ctrl, Alt, lock keys) currently held or toggled.
+pub fn modifiers() -> Modifiers {
+    // the keyboard interrupt takes the same lock
+    x86_64::instructions::interrupts::without_interrupts(|| KEYBOARD.lock().get_modifiers().clone())
+}
+
+/// Feeds a byte read from the keyboard controller to the decoder, returning the key
+/// it completes, if any.
+pub(crate) fn decode(scancode: u8) -> Option<DecodedKey> {
+    if command_reply(scancode) {
+        return None;
+    }
+    let mut keyboard = KEYBOARD.lock();
+    let key = match keyboard.add_byte(scancode) {
+        Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+        _ => None,
+    };
+    if let Some(DecodedKey::RawKey(KeyCode::CapsLock | KeyCode::NumpadLock)) = key {
+        set_leds(keyboard.get_modifiers());
+    }
+    key
+}
+
+/// Keyboard command setting the LEDs; the LED bits follow in a second byte
+const SET_LEDS: u8 = 0xED;
+/// Keyboard command setting how held keys repeat; `TYPEMATIC` follows
+const SET_TYPEMATIC: u8 = 0xF3;
+/// Replies of the keyboard to a command byte
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+
+/// How long a key is held before it starts repeating: 0 to 3 for 250 ms to 1 s
+const REPEAT_DELAY: u8 = 1;
+/// How fast a held key repeats: 0 for 30 per second down to 31 for 2 per second;
+/// 0x0B is about 11 per second
+const REPEAT_RATE: u8 = 0x0B;
+/// Second byte of `SET_TYPEMATIC`: the delay in bits 5-6, the rate in bits 0-4
+const TYPEMATIC: u8 = (REPEAT_DELAY << 5) | REPEAT_RATE;
+
+/// Where the keyboard command in flight is: nothing sent, the command byte sent, or
+/// its data byte sent, each waiting for its `ACK`
+const COMMAND_IDLE: u8 = 0;
+const COMMAND_SENT: u8 = 1;
+const COMMAND_DATA: u8 = 2;
+static COMMAND_STATE: AtomicU8 = AtomicU8::new(COMMAND_IDLE);
+/// Command byte in flight, and the data byte sent after it
+static COMMAND: AtomicU8 = AtomicU8::new(0);
+static COMMAND_DATA_SENT: AtomicU8 = AtomicU8::new(0);
+/// Commands waiting to go out once the one in flight is done
+static TYPEMATIC_PENDING: AtomicBool = AtomicBool::new(false);
+static LEDS_PENDING: AtomicBool = AtomicBool::new(false);
+/// LED bits wanted (bit 1 Num Lock, bit 2 Caps Lock)
+static LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// Updates the keyboard LEDs to the lock key state. Only sends the command; the bits
+/// go out when the keyboard acknowledges it, which arrives as a keyboard interrupt.
+fn set_leds(modifiers: &Modifiers) {
+    let leds = (u8::from(modifiers.numlock) << 1) | (u8::from(modifiers.capslock) << 2);
+    LEDS.store(leds, Ordering::Relaxed);
+    LEDS_PENDING.store(true, Ordering::Relaxed);
+    // one in flight sends the new bits once it's done
+    if COMMAND_STATE.load(Ordering::Relaxed) == COMMAND_IDLE {
+        send_next_command();
+    }
+}
+
+/// Sets the key repeat rate to `REPEAT_DELAY` and `REPEAT_RATE`, and the LEDs to the
+/// state the decoder starts out with. The commands go out one by one as the keyboard
+/// acknowledges them, so they're done once the keyboard interrupt is on.
+pub fn init() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        TYPEMATIC_PENDING.store(true, Ordering::Relaxed);
+        set_leds(KEYBOARD.lock().get_modifiers());
+    });
+}
+
+/// Sends the next pending command byte, if any. Only call while none is in flight.
+fn send_next_command() {
+    let command = if TYPEMATIC_PENDING.swap(false, Ordering::Relaxed) {
+        SET_TYPEMATIC
+    } else if LEDS_PENDING.swap(false, Ordering::Relaxed) {
+        SET_LEDS
+    } else {
+        return;
+    };
+    COMMAND.store(command, Ordering::Relaxed);
+    COMMAND_STATE.store(COMMAND_SENT, Ordering::Relaxed);
+    send_to_keyboard(command);
+}
+
+/// Handles `byte` if it is the keyboard's reply to a command in flight, sending
+/// whatever comes next. Returns whether it was.
+fn command_reply(byte: u8) -> bool {
+    match (COMMAND_STATE.load(Ordering::Relaxed), byte) {
+        (COMMAND_IDLE, _) => false,
+        (COMMAND_SENT, ACK) => {
+            let data = match COMMAND.load(Ordering::Relaxed) {
+                SET_TYPEMATIC => TYPEMATIC,
+                _ => LEDS.load(Ordering::Relaxed),
+            };
+            COMMAND_DATA_SENT.store(data, Ordering::Relaxed);
+            COMMAND_STATE.store(COMMAND_DATA, Ordering::Relaxed);
+            send_to_keyboard(data);
+            true
+        }
+        (COMMAND_DATA, ACK) => {
+            COMMAND_STATE.store(COMMAND_IDLE, Ordering::Relaxed);
+            // e.g. a lock key toggled while the LED bits were on the way
+            send_next_command();
+            true
+        }
+        (COMMAND_SENT, RESEND) => {
+            send_to_keyboard(COMMAND.load(Ordering::Relaxed));
+            true
+        }
+        (COMMAND_DATA, RESEND) => {
+            send_to_keyboard(COMMAND_DATA_SENT.load(Ordering::Relaxed));
+            true
+        }
+        // a key press in between; the reply is still to come
+        _ => false,
+    }
+}
+
+/// Writes `byte` to the keyboard once the controller's input buffer is empty.
+fn send_to_keyboard(byte: u8) {
+    let mut status = Port::<u8>::new(0x64);
+    let mut data = Port::<u8>::new(0x60);
+    unsafe {
+        while status.read() & 0x02 != 0 {}
+        data.write(byte);
+    }
+}
+
+/// Keys `push_key` can hold before further ones are dropped
+const QUEUE_LEN: usize = 64;
+
+/// Ring buffer of decoded keys between the interrupt handlers, which push, and the main
+/// loop, which pops. Without locks, so pushing never waits for whatever the main loop is
+/// doing. There must be only one of each side at a time: the keyboard and serial
+/// interrupts can't interrupt each other, and only the main loop pops.
+struct KeyQueue {
+    keys: [UnsafeCell<DecodedKey>; QUEUE_LEN],
+    /// Keys popped so far; wraps
+    head: AtomicUsize,
+    /// Keys pushed so far; wraps
+    tail: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+unsafe impl Sync for KeyQueue {}
+
+static QUEUE: KeyQueue = KeyQueue {
+    keys: [const { UnsafeCell::new(DecodedKey::Unicode('\0')) }; QUEUE_LEN],
+    head: AtomicUsize::new(0),
+    tail: AtomicUsize::new(0),
+    dropped: AtomicUsize::new(0),
+};
+
+/// Queues `key` for `pop_key`. Returns false, dropping the key, if the queue is full.
+pub fn push_key(key: DecodedKey) -> bool {
+    let tail = QUEUE.tail.load(Ordering::Relaxed);
+    if tail.wrapping_sub(QUEUE.head.load(Ordering::Acquire)) == QUEUE_LEN {
+        QUEUE.dropped.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
+    unsafe { *QUEUE.keys[tail % QUEUE_LEN].get() = key };
+    QUEUE.tail.store(tail.wrapping_add(1), Ordering::Release);
+    true
+}
+
+/// Takes the oldest queued key.
+pub fn pop_key() -> Option<DecodedKey> {
+    let head = QUEUE.head.load(Ordering::Relaxed);
+    if head == QUEUE.tail.load(Ordering::Acquire) {
+        return None;
+    }
+    let key = unsafe { *QUEUE.keys[head % QUEUE_LEN].get() };
+    QUEUE.head.store(head.wrapping_add(1), Ordering::Release);
+    Some(key)
+}
+
+/// If `key` is queued, drops it and every key queued before it, and returns true. For
+/// long running commands checking for Ctrl+C: like a terminal, an interrupt throws away
+/// what was typed ahead.
+pub fn discard_through(key: DecodedKey) -> bool {
+    let head = QUEUE.head.load(Ordering::Relaxed);
+    let tail = QUEUE.tail.load(Ordering::Acquire);
+    let queued = tail.wrapping_sub(head);
+    match (0..queued).find(|&i| unsafe { *QUEUE.keys[head.wrapping_add(i) % QUEUE_LEN].get() } == key) {
+        Some(i) => {
+            QUEUE.head.store(head.wrapping_add(i + 1), Ordering::Release);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Whether `pop_key` has anything to give.
+pub fn has_keys() -> bool {
+    QUEUE.head.load(Ordering::Relaxed) != QUEUE.tail.load(Ordering::Acquire)
+}
+
+/// Number of keys dropped so far because the queue was full.
+pub fn dropped_keys() -> usize {
+    QUEUE.dropped.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn queue_keeps_keys_in_order_under_contention() {
+        const KEYS: u32 = 50_000;
+        let key = |i: u32| DecodedKey::Unicode(char::from_u32(i % 0xD800).unwrap());
+        let producer = thread::spawn(move || {
+            for i in 0..KEYS {
+                while !push_key(key(i)) {
+                    thread::yield_now();
+                }
+            }
+        });
+        for i in 0..KEYS {
+            let popped = loop {
+                match pop_key() {
+                    Some(popped) => break popped,
+                    None => thread::yield_now(),
+                }
+            };
+            assert_eq!(popped, key(i), "key {} out of order", i);
+        }
+        producer.join().unwrap();
+        assert!(!has_keys());
+    }
+}
@@ -1,20 +1,37 @@
 // Original code from rust-osdev/bootloader crate https://github.com/rust-osdev/bootloader
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(abi_x86_interrupt)]
 
 use core::cell::UnsafeCell;
-use core::panic::PanicInfo;
-use core::fmt::Write;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::fmt::{self, Write};
 use uart_16550::SerialPort;
 use pc_keyboard::DecodedKey;
 
-mod interrupts;
+pub mod cpuid;
+pub mod gdt;
+pub mod interrupts;
+pub mod keyboard;
+pub mod log;
+pub mod paging;
+pub mod power;
+pub mod rtc;
+pub mod speaker;
+pub mod sync;
+pub mod time;
+pub mod usermode;
 
 extern crate alloc;
 
+static SERIAL_READY: AtomicBool = AtomicBool::new(false);
+
 pub fn serial() -> SerialPort {
     let mut port = unsafe { SerialPort::new(0x3F8) };
-    port.init();
+    // initializing clears the receive queue, so only do it once
+    if !SERIAL_READY.swap(true, Ordering::Relaxed) {
+        port.init();
+    }
     port
 }
 
@@ -25,25 +42,30 @@ pub fn serial() -> SerialPort {
 /// interrupt operating system.
 ///
 /// For now, it only includes timer and keyboard handlers.
+#[derive(Clone, Copy)]
 pub struct HandlerTable {
     timer: Option<fn()>,
     keyboard: Option<fn(DecodedKey)>,
+    page_fault: Option<fn(x86_64::VirtAddr) -> bool>,
     startup: Option<fn()>,
+    irq_enter: Option<fn()>,
+    irq_exit: Option<fn()>,
     cpu_loop: fn() -> !,
 }
 
 impl HandlerTable {
     /// Creates a new HandlerTable with no handlers.
     pub fn new() -> Self {
-        HandlerTable {timer: None, keyboard: None, startup: None, cpu_loop: hlt_loop}
+        HandlerTable {timer: None, keyboard: None, page_fault: None, startup: None, irq_enter: None, irq_exit: None, cpu_loop: hlt_loop}
     }
 
-    /// Starts up a simple operating system using the specified handlers.
-    pub fn start(self, lapic_ptr: *mut u32) -> ! {
+    /// Starts up a simple operating system using the specified handlers, with IRQs
+    /// coming from `backend` (set up with `interrupts::init_apic` or `init_pic`).
+    pub fn start(self, backend: interrupts::InterruptBackend) -> ! {
         self.startup.map(|f| f());
         let fore = self.cpu_loop;
         
-        interrupts::init_idt(self, lapic_ptr);
+        interrupts::init_idt(self, backend);
         
         (fore)();
     }
@@ -78,6 +100,22 @@ impl HandlerTable {
         }
     }
 
+    /// Sets the page fault handler, called with the faulting address when a page that
+    /// isn't present is accessed. It returns whether it mapped the page, so that the
+    /// access can be retried; otherwise the fault is reported and the CPU halts.
+    ///
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn page_fault(mut self, page_fault_handler: fn(x86_64::VirtAddr) -> bool) -> Self {
+        self.page_fault = Some(page_fault_handler);
+        self
+    }
+
+    /// Called by the low-level interrupt routines to handle a fault on a page that isn't
+    /// present. Returns whether the handler mapped it.
+    pub fn handle_page_fault(&self, address: x86_64::VirtAddr) -> bool {
+        self.page_fault.is_some_and(|page_fault| (page_fault)(address))
+    }
+
     /// Sets the startup handler.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
     pub fn startup(mut self, startup_handler: fn()) -> Self {
@@ -85,6 +123,18 @@ impl HandlerTable {
         self
     }
 
+    /// Sets functions called as the timer, keyboard and serial interrupt handlers start
+    /// (`enter`) and as they finish (`exit`), so the kernel can tell when it's running
+    /// inside one. A handler that switches tasks only calls `exit` once the task it
+    /// interrupted runs again.
+    ///
+    /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
+    pub fn irq_hooks(mut self, enter: fn(), exit: fn()) -> Self {
+        self.irq_enter = Some(enter);
+        self.irq_exit = Some(exit);
+        self
+    }
+
     /// Sets the cpu loop handler.
     /// This function should contain an infinite loop.
     /// Returns Self for chained [Builder pattern construction](https://doc.rust-lang.org/1.0.0/style/ownership/builders.html).
@@ -94,15 +144,79 @@ impl HandlerTable {
     }
 }
 
+static CONSOLE: RacyCell<Option<fn(fmt::Arguments)>> = RacyCell::new(None);
+
+/// Sets a function that fault reports are shown with, in addition to serial. Set it once
+/// the screen is up; until then reports only go to serial.
+pub fn set_console(console: fn(fmt::Arguments)) {
+    *unsafe { CONSOLE.get_mut() } = Some(console);
+}
+
+/// Writes a fault report over serial and to the console, if one is set.
+pub fn report(args: fmt::Arguments) {
+    let _ = serial().write_fmt(args);
+    if let Some(console) = unsafe { *CONSOLE.get_mut() } {
+        console(args);
+    }
+}
+
 pub fn hlt_loop() -> ! {
     loop {
         x86_64::instructions::hlt();
     }
 }
 
+/// Frames the panic backtrace shows at most
+#[cfg(target_os = "none")]
+const BACKTRACE_FRAMES: usize = 16;
+
+/// Stack the running code is on, so the backtrace never follows `rbp` anywhere else
+static STACK_START: AtomicU64 = AtomicU64::new(0);
+static STACK_END: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the address range of the stack in use; the scheduler calls it on each switch.
+pub fn set_stack(stack: Range<u64>) {
+    STACK_START.store(stack.start, Ordering::Relaxed);
+    STACK_END.store(stack.end, Ordering::Relaxed);
+}
+
+/// Returns the range last given to `set_stack`.
+pub fn stack() -> Range<u64> {
+    STACK_START.load(Ordering::Relaxed)..STACK_END.load(Ordering::Relaxed)
+}
+
+/// Reports the return addresses of up to `BACKTRACE_FRAMES` callers by following the
+/// saved `rbp` chain, which the build keeps with `force-frame-pointers`. Stops at a
+/// null or misaligned `rbp`, one outside the stack, or one that doesn't lead up the
+/// stack, so a clobbered chain ends the walk instead of faulting.
+#[cfg(target_os = "none")]
+fn backtrace() {
+    let stack = stack();
+    let mut rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    report(format_args!("backtrace:\n"));
+    for frame in 0..BACKTRACE_FRAMES {
+        // the frame is the saved rbp followed by the return address
+        if rbp == 0 || rbp % 8 != 0 || rbp < stack.start || rbp.saturating_add(16) > stack.end {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        report(format_args!("  #{frame:<2} {ret:#018x}\n"));
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+}
+
+// host test builds get the panic handler from std
+#[cfg(target_os = "none")]
 #[panic_handler]
-fn panic(info: &PanicInfo) -> ! {
-    let _ = writeln!(serial(), "PANIC: {info}");
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    // nothing else gets to run, not even the timer
+    x86_64::instructions::interrupts::disable();
+    report(format_args!("PANIC: {info}\n"));
+    backtrace();
     hlt_loop();
 }
 
@@ -124,4 +238,60 @@ impl<T> RacyCell<T> {
 }
 
 unsafe impl<T> Send for RacyCell<T> where T: Send {}
-unsafe impl<T: Sync> Sync for RacyCell<T> {}
\ No newline at end of file
+unsafe impl<T: Sync> Sync for RacyCell<T> {}
+
+/// Text of at most `N` bytes, formatted into with `write!` without allocating, e.g. from
+/// an interrupt handler. Whatever doesn't fit is cut off at a character boundary.
+#[derive(Clone, Copy)]
+pub struct ArrayString<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> ArrayString<N> {
+    pub const fn new() -> Self {
+        Self { bytes: [0; N], len: 0 }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever filled with whole characters
+        unsafe { core::str::from_utf8_unchecked(&self.bytes[..self.len]) }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> Default for ArrayString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Write for ArrayString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = N - self.len;
+        let fits = (0..=s.len().min(room)).rev().find(|&i| s.is_char_boundary(i)).unwrap_or(0);
+        self.bytes[self.len..self.len + fits].copy_from_slice(&s.as_bytes()[..fits]);
+        self.len += fits;
+        if fits == s.len() { Ok(()) } else { Err(fmt::Error) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn array_strings_cut_off_at_a_character() {
+        let mut text = ArrayString::<8>::new();
+        write!(text, "{}-{}", 12, 34).unwrap();
+        assert_eq!(text.as_str(), "12-34");
+        // 'é' takes two bytes, and only one is left after "12-34ab"
+        assert!(write!(text, "abé").is_err());
+        assert_eq!(text.as_str(), "12-34ab");
+        text.clear();
+        assert_eq!(text.as_str(), "");
+    }
+}
@@ -0,0 +1,153 @@
+//! Leveled logging over serial. Each line is tagged with the tick count and the level;
+//! lines below the level set with `set_level` are dropped. Lines also go to a ring
+//! buffer for `dmesg`. Nothing allocates, so the macros work in interrupt handlers too.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::{interrupts, serial};
+
+/// How important a log line is, least important first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    const ALL: [Level; 4] = [Level::Debug, Level::Info, Level::Warn, Level::Error];
+
+    /// Parses a level name as `loglevel` takes it, ignoring case.
+    pub fn parse(name: &str) -> Option<Level> {
+        Self::ALL.into_iter().find(|level| level.name().eq_ignore_ascii_case(name))
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+/// Sets the least important level that still gets logged.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns the least important level that still gets logged.
+pub fn level() -> Level {
+    Level::ALL[usize::from(LEVEL.load(Ordering::Relaxed))]
+}
+
+/// Writes one log line; use the `debug!`, `info!`, `warn!` and `error!` macros instead.
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments) {
+    // host unit tests have no serial port to write to
+    if level < self::level() || cfg!(not(target_os = "none")) {
+        return;
+    }
+    let ticks = interrupts::current_ticks();
+    let mut serial = serial();
+    write!(serial, "[{:>8} {:<5}] ", ticks, level.name()).ok();
+    serial.write_fmt(args).ok();
+    serial.write_char('\n').ok();
+    without_interrupts(|| {
+        // only busy if a fault hit while the ring was being written; skip the line then
+        if let Some(mut ring) = RING.try_lock() {
+            writeln!(ring, "[{:>8} {:<5}] {}", ticks, level.name(), args).ok();
+        }
+    });
+}
+
+/// Bytes of log kept for `dmesg`
+const RING_SIZE: usize = 16 * 1024;
+
+/// The most recent log lines, oldest first, as bytes from `start` on, wrapping around
+struct Ring {
+    bytes: [u8; RING_SIZE],
+    start: usize,
+    len: usize,
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring { bytes: [0; RING_SIZE], start: 0, len: 0 });
+
+impl Ring {
+    /// Drops the oldest line, or everything if there's no full line.
+    fn drop_line(&mut self) {
+        while self.len > 0 {
+            let byte = self.bytes[self.start];
+            self.start = (self.start + 1) % RING_SIZE;
+            self.len -= 1;
+            if byte == b'\n' {
+                break;
+            }
+        }
+    }
+}
+
+impl Write for Ring {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.len == RING_SIZE {
+                self.drop_line();
+            }
+            self.bytes[(self.start + self.len) % RING_SIZE] = byte;
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Calls `f` with the logged lines kept in the ring buffer, oldest first, in two
+/// parts that follow each other. Interrupts are off meanwhile, so copy them out
+/// rather than printing them from `f`.
+pub fn with_dmesg<R>(f: impl FnOnce(&[u8], &[u8]) -> R) -> R {
+    without_interrupts(|| {
+        let ring = RING.lock();
+        let end = ring.start + ring.len;
+        if end <= RING_SIZE {
+            f(&ring.bytes[ring.start..end], &[])
+        } else {
+            f(&ring.bytes[ring.start..], &ring.bytes[..end - RING_SIZE])
+        }
+    })
+}
+
+/// Logs a line at `Level::Debug`, with `format!` arguments.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+/// Logs a line at `Level::Info`, with `format!` arguments.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+/// Logs a line at `Level::Warn`, with `format!` arguments.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+/// Logs a line at `Level::Error`, with `format!` arguments.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Error, format_args!($($arg)*)) };
+}
@@ -1,32 +1,41 @@
 #![feature(sync_unsafe_cell)]
 #![feature(abi_x86_interrupt)]
-#![no_std] // don't link the Rust standard library
-#![no_main] // disable all Rust-level entry points
+#![cfg_attr(not(test), no_std)] // don't link the Rust standard library
+#![cfg_attr(not(test), no_main)] // disable all Rust-level entry points
+// unit tests run on the host, where nothing calls kernel_main
+#![cfg_attr(test, allow(dead_code, unused_imports))]
 
 extern crate alloc;
 
 mod screen;
+mod args;
+mod calc;
+mod conv;
 mod allocator;
+mod demand;
 mod frame_allocator;
-mod interrupts;
-mod gdt;
+mod fs;
+mod memory;
+mod mmio;
+mod selftest;
+mod shell;
+mod syscall;
+mod table;
+mod task;
+mod user;
 
 use alloc::boxed::Box;
-use alloc::string::String;
-use alloc::vec::Vec;
 use core::fmt::Write;
 use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
 use bootloader_api::config::Mapping::Dynamic;
 use bootloader_api::info::MemoryRegionKind;
-use kernel::{HandlerTable, serial};
-use lazy_static::lazy_static;
+use kernel::{HandlerTable, gdt, interrupts, keyboard, log, time};
 use pc_keyboard::{DecodedKey, KeyCode};
-use spin::Mutex;
 use x86_64::registers::control::Cr3;
 use x86_64::VirtAddr;
-use crate::frame_allocator::BootInfoFrameAllocator;
-use crate::screen::{Writer, screenwriter};
+use crate::screen::{Color, Writer, screenwriter, try_screenwriter};
 
 const BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
@@ -34,131 +43,96 @@ const BOOTLOADER_CONFIG: BootloaderConfig = {
     config.kernel_stack_size = 256 * 1024; // 256 KiB kernel stack size
     config
 };
+#[cfg(not(test))]
 entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
-struct Shell {
-    buf: String,
-    ticks: u64,
-}
+/// Timer frequency asked for at startup
+const TIMER_HZ: u64 = 100;
 
-impl Shell {
-    fn new() -> Self { Self { buf: String::new(), ticks: 0 } }
-    fn prompt(&self) {
-        write!(Writer, "> ").ok();
-    }
-    fn redraw_line(&self) {
-        // erase current line by rewriting spaces, then redraw prompt + buffer
-        let len = 2 + self.buf.len();
-        write!(Writer, "\r").ok();
-        for _ in 0..len { write!(Writer, " ").ok(); }
-        write!(Writer, "\r").ok();
-        write!(Writer, "> ").ok();
-        write!(Writer, "{}", self.buf).ok();
-    }
-    fn handle_key(&mut self, key: DecodedKey) {
-        match key {
-            DecodedKey::Unicode('\n') => {
-                writeln!(Writer, "").ok();
-                self.execute();
-                self.buf.clear();
-                self.prompt();
-            }
-            DecodedKey::Unicode(c) => {
-                self.buf.push(c);
-                write!(Writer, "{}", c).ok();
-            }
-            DecodedKey::RawKey(KeyCode::Backspace) => {
-                if !self.buf.is_empty() {
-                    self.buf.pop();
-                    self.redraw_line();
-                }
-            }
-            _ => {}
-        }
-    }
-    fn execute(&mut self) {
-        let input = core::mem::take(&mut self.buf);
-        let mut parts = input.split_whitespace();
-        if let Some(cmd) = parts.next() {
-            let args: Vec<&str> = parts.collect();
-            match cmd {
-                "echo" => {
-                    if !args.is_empty() { writeln!(Writer, "{}", args.join(" ")).ok(); }
-                }
-                "clear" => {
-                    screenwriter().clear();
-                }
-                "ticks" => {
-                    writeln!(Writer, "{}", self.ticks).ok();
-                }
-                "memstat" => {
-                    let (used, total) = allocator::memstat();
-                    writeln!(Writer, "used: {} / {} bytes", used, total).ok();
-                }
-                "help" => {
-                    writeln!(Writer, "Built-ins:").ok();
-                    writeln!(Writer, "  echo [text...]  - print text").ok();
-                    writeln!(Writer, "  clear           - clear screen").ok();
-                    writeln!(Writer, "  ticks           - show timer ticks").ok();
-                    writeln!(Writer, "  memstat         - show allocator usage").ok();
-                    writeln!(Writer, "  help            - this message").ok();
-                }
-                _ => {
-                    writeln!(Writer, "unknown: {}", cmd).ok();
-                }
-            }
-        }
-    }
-}
+/// Use the 8259 PIC and the PIT even when there is an APIC, to try the fallback
+const FORCE_PIC: bool = false;
 
-lazy_static! {
-    static ref SHELL: Mutex<Shell> = Mutex::new(Shell::new());
-}
+/// Least important log level shown from startup on; `loglevel` changes it later
+const LOG_LEVEL: log::Level = log::Level::Info;
+
+/// Rows moved per Shift+PageUp / Shift+PageDown
+const SCROLL_ROWS: usize = 10;
+
+/// TSC cycles a key press may come after another without a timer tick in between
+/// before the timer counts as stalled; one to a few seconds on current CPUs
+const STALL_CYCLES: u64 = 3_000_000_000;
+
+/// Tick count the keyboard handler saw last, and the TSC when it first saw it
+static WATCHDOG_TICKS: AtomicU64 = AtomicU64::new(0);
+static WATCHDOG_TSC: AtomicU64 = AtomicU64::new(0);
+/// Set once a stall has been reported, until the timer ticks again
+static WATCHDOG_WARNED: AtomicBool = AtomicBool::new(false);
 
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
-    writeln!(serial(), "Entered kernel with boot info: {boot_info:?}").unwrap();
-    writeln!(serial(), "Frame Buffer: {:p}", boot_info.framebuffer.as_ref().unwrap().buffer()).unwrap();
-
-    let frame_info = boot_info.framebuffer.as_ref().unwrap().info();
-    let framebuffer = boot_info.framebuffer.as_mut().unwrap();
-    screen::init(framebuffer);
-    for x in 0..frame_info.width {
-        screenwriter().draw_pixel(x, frame_info.height-15, 0xff, 0, 0);
-        screenwriter().draw_pixel(x, frame_info.height-10, 0, 0xff, 0);
-        screenwriter().draw_pixel(x, frame_info.height-5, 0, 0, 0xff);
+    log::set_level(LOG_LEVEL);
+    // we start out close to the top of the kernel stack, which ends on a page boundary
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let stack_top = rsp.next_multiple_of(4096);
+    kernel::set_stack(stack_top - BOOTLOADER_CONFIG.kernel_stack_size..stack_top);
+    // catch faults from the raw memory accesses below instead of triple faulting
+    gdt::init();
+    interrupts::load_idt();
+    syscall::init();
+
+    kernel::debug!("Entered kernel with boot info: {boot_info:?}");
+    match boot_info.framebuffer.as_ref() {
+        Some(framebuffer) => kernel::info!("Frame Buffer: {:p}", framebuffer.buffer()),
+        None => kernel::info!("No frame buffer, using the serial console only"),
     }
 
     for r in boot_info.memory_regions.iter() {
-        writeln!(serial(), "{:?} {:?} {:?} {}", r, r.start as *mut u8, r.end as *mut usize, r.end-r.start).unwrap();
+        kernel::debug!("{:?} {:?} {:?} {}", r, r.start as *mut u8, r.end as *mut usize, r.end-r.start);
     }
 
     let usable_region = boot_info.memory_regions.iter().filter(|x|x.kind == MemoryRegionKind::Usable).last().unwrap();
-    writeln!(serial(), "{usable_region:?}").unwrap();
+    kernel::debug!("{usable_region:?}");
 
     let physical_offset = boot_info.physical_memory_offset.take().expect("Failed to find physical memory offset");
+    memory::init(physical_offset, &boot_info.memory_regions, boot_info.kernel_len);
     let ptr = (physical_offset + usable_region.start) as *mut u8;
-    writeln!(serial(), "Physical memory offset: {:X}; usable range: {:p}", physical_offset, ptr).unwrap();
+    kernel::debug!("Physical memory offset: {:X}; usable range: {:p}", physical_offset, ptr);
 
     // print out values stored in specific memory address
     let vault = unsafe { slice::from_raw_parts_mut(ptr, 100) };
     vault[0] = 65;
     vault[1] = 66;
-    writeln!(Writer, "{} {}", vault[0] as char, vault[1] as char).unwrap();
+    let vault_text = (vault[0] as char, vault[1] as char);
 
     //read CR3 for current page table
     let cr3 = Cr3::read().0.start_address().as_u64();
-    writeln!(serial(), "CR3 read: {:#x}", cr3).unwrap();
+    kernel::debug!("CR3 read: {:#x}", cr3);
 
     let cr3_page = unsafe { slice::from_raw_parts_mut((cr3 + physical_offset) as *mut usize, 6) };
-    writeln!(serial(), "CR3 Page table virtual address {cr3_page:#p}").unwrap();
+    kernel::debug!("CR3 Page table virtual address {cr3_page:#p}");
+
+    // the heap takes over the usable region above; the screen needs it for its scrollback
+    allocator::init_heap((physical_offset + usable_region.start) as usize, (usable_region.end - usable_region.start) as usize);
 
-    allocator::init_heap((physical_offset + usable_region.start) as usize);
+    // without a framebuffer, Writer goes to serial and fault reports only go there anyway
+    if let Some(framebuffer) = boot_info.framebuffer.as_mut() {
+        let frame_info = framebuffer.info();
+        screen::init(framebuffer);
+        kernel::set_console(screen::console);
+        let (width, height) = (frame_info.width as isize, frame_info.height as isize);
+        let mut screen = screenwriter().unwrap();
+        screen.draw_line(0, height-15, width-1, height-15, Color::new(0xff, 0, 0));
+        screen.draw_line(0, height-10, width-1, height-10, Color::new(0, 0xff, 0));
+        screen.draw_line(0, height-5, width-1, height-5, Color::new(0, 0, 0xff));
+        screen.present();
+    }
+    writeln!(Writer, "{} {}", vault_text.0, vault_text.1).unwrap();
 
     let rsdp = boot_info.rsdp_addr.take();
-    let mut mapper = frame_allocator::init(VirtAddr::new(physical_offset));
-    let mut frame_allocator = BootInfoFrameAllocator::new(&boot_info.memory_regions);
-    
-    gdt::init();
+    frame_allocator::set_mapper(frame_allocator::init(VirtAddr::new(physical_offset)));
+    frame_allocator::init_frames(&boot_info.memory_regions);
+    // the heap took over this region, so no frames from it
+    frame_allocator::with_frames(|frames| frames.reserve(usable_region.start..usable_region.end));
 
     // print out values from heap allocation
     let x = Box::new(42);
@@ -167,26 +141,131 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     writeln!(Writer, "{x:#p} {:?}", *x).unwrap();
     writeln!(Writer, "{y:#p} {:?}", *y).unwrap();
     
-    writeln!(serial(), "Starting kernel...").unwrap();
+    kernel::info!("Starting kernel...");
 
-    let lapic_ptr = interrupts::init_apic(rsdp.expect("Failed to get RSDP address") as usize, physical_offset, &mut mapper, &mut frame_allocator);
+    // the APIC is found through the ACPI tables; without them, fall back to the 8259
+    let backend = match rsdp {
+        _ if FORCE_PIC => {
+            kernel::info!("Interrupts: 8259 PIC and PIT, as FORCE_PIC is set");
+            interrupts::init_pic()
+        }
+        Some(rsdp) => {
+            kernel::info!("Interrupts: APIC, RSDP at {:#x}", rsdp);
+            frame_allocator::with_mapper(|mapper, frames| interrupts::init_apic(rsdp as usize, physical_offset, mapper, frames))
+        }
+        None => {
+            kernel::info!("Interrupts: no RSDP, falling back to the 8259 PIC and the PIT");
+            interrupts::init_pic()
+        }
+    };
+    interrupts::set_timer_hz(TIMER_HZ);
+    time::init();
+    task::init();
+    keyboard::init();
     HandlerTable::new()
         .keyboard(key)
         .timer(tick)
+        .page_fault(demand::handle_fault)
+        .irq_hooks(allocator::enter_irq, allocator::exit_irq)
         .startup(start)
-        .start(lapic_ptr)
+        .cpu_loop(main_loop)
+        .start(backend)
 }
 
 fn start() {
-    SHELL.lock().prompt();
+    let mut shell = shell::SHELL.lock();
+    shell.print_motd(&mut Writer);
+    shell.prompt();
 }
 
 fn tick() {
-    // increment tick counter; avoid cluttering screen with dots
-    let mut sh = SHELL.lock();
-    sh.ticks = sh.ticks.wrapping_add(1);
+    // `irqdemo` holds this lock with interrupts off, so this never spins on it
+    *shell::DEMO_TICKS.lock() += 1;
+    // toggle the cursor twice a second, unless the shell is in the middle of drawing
+    let half_second = (interrupts::timer_hz() / 2).max(1);
+    if interrupts::current_ticks() % half_second == 0 && !shell::SHELL.is_locked() {
+        if let Some(mut screen) = try_screenwriter() {
+            screen.blink_cursor();
+        }
+    }
+    if interrupts::current_ticks() % interrupts::timer_hz().max(1) == 0 && !shell::SHELL.is_locked() {
+        update_status();
+    }
+    task::preempt();
+}
+
+/// Refreshes the status bar with the uptime and memory use. Called from the timer, so it
+/// doesn't allocate, and skips the update while the screen or the frame allocator is
+/// locked.
+fn update_status() {
+    let Some(mut screen) = try_screenwriter() else { return };
+    if !screen.has_status_bar() {
+        return;
+    }
+    let Some((frames_used, frames_total)) = frame_allocator::try_with_frames(|frames| (frames.frames_used(), frames.frames_total())) else { return };
+    let seconds = interrupts::current_ticks() / interrupts::timer_hz().max(1);
+    let heap = allocator::snapshot();
+    let modifiers = keyboard::modifiers();
+    screen.set_status(format_args!(" up {}:{:02}:{:02} | heap {} / {} KiB | frames {} / {} | {} tasks{}{}",
+        seconds / 3600, seconds / 60 % 60, seconds % 60, heap.used / 1024, heap.total / 1024,
+        frames_used, frames_total, task::count(),
+        if modifiers.capslock { " | CAPS" } else { "" }, if modifiers.numlock { " | NUM" } else { "" }));
 }
 
+/// Only queues the key; the main loop does the work, so the interrupt stays short.
 fn key(key: DecodedKey) {
-    SHELL.lock().handle_key(key);
+    watchdog();
+    if !keyboard::push_key(key) {
+        kernel::warn!("key queue full, dropped {:?}", key);
+    }
+}
+
+/// Warns over serial if keys keep arriving while the timer doesn't tick, since then
+/// nothing that waits for it (cursor, `sleep`, tasks) works any more.
+fn watchdog() {
+    let ticks = interrupts::current_ticks();
+    let now = time::rdtsc();
+    if ticks != WATCHDOG_TICKS.load(Ordering::Relaxed) || WATCHDOG_TSC.load(Ordering::Relaxed) == 0 {
+        WATCHDOG_TICKS.store(ticks, Ordering::Relaxed);
+        WATCHDOG_TSC.store(now, Ordering::Relaxed);
+        WATCHDOG_WARNED.store(false, Ordering::Relaxed);
+    } else if now.wrapping_sub(WATCHDOG_TSC.load(Ordering::Relaxed)) > STALL_CYCLES
+        && !WATCHDOG_WARNED.swap(true, Ordering::Relaxed)
+    {
+        kernel::warn!("watchdog: no timer tick since tick {}, the timer may be stalled", ticks);
+    }
+}
+
+/// Hands queued keys to the shell, halting while there are none.
+fn main_loop() -> ! {
+    loop {
+        while let Some(key) = keyboard::pop_key() {
+            handle_key(key);
+        }
+        // check and halt with interrupts off, so a key arriving in between still
+        // wakes the halt instead of waiting for the next tick
+        x86_64::instructions::interrupts::disable();
+        if keyboard::has_keys() {
+            x86_64::instructions::interrupts::enable();
+        } else {
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
+}
+
+fn handle_key(key: DecodedKey) {
+    let shifted = keyboard::modifiers().is_shifted();
+    match key {
+        // under the screen lock, so the timer can't blink the cursor or redraw the status
+        // bar halfway through
+        DecodedKey::RawKey(code @ (KeyCode::PageUp | KeyCode::PageDown)) if shifted => match screenwriter() {
+            Some(mut screen) if code == KeyCode::PageUp => screen.scroll_up(SCROLL_ROWS),
+            Some(mut screen) => screen.scroll_down(SCROLL_ROWS),
+            None => shell::SHELL.lock().handle_key(key),
+        },
+        // show the new lock state right away instead of with the next second
+        DecodedKey::RawKey(KeyCode::CapsLock | KeyCode::NumpadLock) => update_status(),
+        // keys typed while a command is still running (e.g. `sleep`) wait in the queue
+        key => shell::SHELL.lock().handle_key(key),
+    }
 }
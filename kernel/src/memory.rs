@@ -0,0 +1,154 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
+use kernel::RacyCell;
+use crate::{allocator, frame_allocator};
+
+/// Virtual address at which the bootloader mapped all of physical memory
+static PHYSICAL_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Memory map from the bootloader
+static REGIONS: RacyCell<&'static [MemoryRegion]> = RacyCell::new(&[]);
+/// Size of the kernel image in memory
+static KERNEL_LEN: AtomicU64 = AtomicU64::new(0);
+
+/// Remembers the boot memory map and the size of the kernel image for the shell
+/// commands that inspect memory.
+pub fn init(physical_offset: u64, regions: &'static [MemoryRegion], kernel_len: u64) {
+    PHYSICAL_OFFSET.store(physical_offset, Ordering::Relaxed);
+    *unsafe { REGIONS.get_mut() } = regions;
+    KERNEL_LEN.store(kernel_len, Ordering::Relaxed);
+}
+
+pub fn regions() -> &'static [MemoryRegion] {
+    unsafe { REGIONS.get_mut() }
+}
+
+/// Translates the physical range `addr..addr + len` to its virtual address, if it lies
+/// within one region of the memory map.
+pub fn phys_to_virt(addr: u64, len: u64) -> Option<*mut u8> {
+    let end = addr.checked_add(len)?;
+    regions().iter()
+        .find(|r| r.start <= addr && end <= r.end)
+        .map(|_| (PHYSICAL_OFFSET.load(Ordering::Relaxed) + addr) as *mut u8)
+}
+
+/// Writes a walking-ones pattern through `memory` and reads it back, once for each bit
+/// position: on pass `n` the byte at offset `i` gets bit `(i + n) % 8` set, so
+/// neighbouring bytes always differ. Calls `mismatch(offset, expected, found)` for
+/// every byte that reads back wrong and returns how many did. Leaves `memory` zeroed.
+pub fn walking_ones(memory: &mut [u8], mut mismatch: impl FnMut(usize, u8, u8)) -> usize {
+    let pattern = |pass: usize, offset: usize| 1u8 << ((offset + pass) % 8);
+    let mut errors = 0;
+    let ptr = memory.as_mut_ptr();
+    for pass in 0..8 {
+        // volatile, so the compiler can't answer the reads from what it just wrote
+        for offset in 0..memory.len() {
+            unsafe { ptr.add(offset).write_volatile(pattern(pass, offset)) };
+        }
+        for offset in 0..memory.len() {
+            let found = unsafe { ptr.add(offset).read_volatile() };
+            if found != pattern(pass, offset) {
+                mismatch(offset, pattern(pass, offset), found);
+                errors += 1;
+            }
+        }
+    }
+    memory.fill(0);
+    errors
+}
+
+/// Where physical memory went, in bytes. `usable == allocated + free`, and usable, the
+/// bootloader regions and the reserved ones add up to `total`, give or take the partial
+/// frames at the edges of the usable regions.
+#[derive(Debug, Clone, Copy)]
+pub struct MemInfo {
+    /// Everything in the memory map
+    pub total: u64,
+    /// Regions the firmware keeps, or that aren't RAM at all
+    pub reserved: u64,
+    /// Regions the bootloader used, the kernel image, page tables and boot stack among them
+    pub bootloader: u64,
+    pub kernel_image: u64,
+    /// Whole frames in the usable regions
+    pub usable: u64,
+    /// Frames taken from the frame allocator, the heap's included
+    pub allocated: u64,
+    pub heap_used: u64,
+    pub heap_total: u64,
+    pub free: u64,
+}
+
+pub fn meminfo() -> MemInfo {
+    let (used, frames) = frame_allocator::with_frames(|frames| (frames.frames_used(), frames.frames_total()));
+    meminfo_from(regions(), used, frames, allocator::memstat(), KERNEL_LEN.load(Ordering::Relaxed))
+}
+
+/// Works out `MemInfo` from the memory map, the frame allocator's used and total frames,
+/// the heap's (used, total) bytes and the size of the kernel image.
+fn meminfo_from(regions: &[MemoryRegion], used_frames: usize, total_frames: usize, heap: (usize, usize), kernel_image: u64) -> MemInfo {
+    let (mut total, mut reserved, mut bootloader) = (0, 0, 0);
+    for region in regions {
+        let size = region.end - region.start;
+        total += size;
+        match region.kind {
+            MemoryRegionKind::Usable => {}
+            MemoryRegionKind::Bootloader => bootloader += size,
+            _ => reserved += size,
+        }
+    }
+    MemInfo {
+        total,
+        reserved,
+        bootloader,
+        kernel_image,
+        usable: total_frames as u64 * 4096,
+        allocated: used_frames as u64 * 4096,
+        heap_used: heap.0 as u64,
+        heap_total: heap.1 as u64,
+        free: (total_frames - used_frames) as u64 * 4096,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootloader_api::info::MemoryRegions;
+    use crate::frame_allocator::BitmapFrameAllocator;
+    use x86_64::structures::paging::FrameAllocator;
+
+    #[test]
+    fn walking_ones_passes_on_good_memory_and_zeroes_it() {
+        let mut memory = [0xffu8; 100];
+        let mut mismatches = 0;
+        assert_eq!(walking_ones(&mut memory, |_, _, _| mismatches += 1), 0);
+        assert_eq!(mismatches, 0);
+        assert!(memory.iter().all(|&byte| byte == 0));
+        assert_eq!(walking_ones(&mut [], |_, _, _| panic!("nothing to mismatch")), 0);
+    }
+
+    #[test]
+    fn meminfo_adds_up() {
+        let region = |start, end, kind| MemoryRegion { start, end, kind };
+        let regions = vec![
+            // neither usable region starts and ends on a frame boundary
+            region(0x1000, 0x9_f800, MemoryRegionKind::Usable),
+            region(0x9_f800, 0x10_0000, MemoryRegionKind::UnknownBios(1)),
+            region(0x10_0000, 0x18_0000, MemoryRegionKind::Bootloader),
+            region(0x18_0000, 0x20_0800, MemoryRegionKind::UnknownUefi(7)),
+            region(0x20_0800, 0x40_0000, MemoryRegionKind::Usable),
+        ];
+        let memory_map = std::boxed::Box::leak(std::boxed::Box::new(MemoryRegions::from(regions.clone().leak())));
+        let mut frames = BitmapFrameAllocator::new(memory_map);
+        for _ in 0..10 {
+            frames.allocate_frame().unwrap();
+        }
+        let info = meminfo_from(&regions, frames.frames_used(), frames.frames_total(), (1024, 4096), 0x8000);
+        assert_eq!(info.allocated, 10 * 4096);
+        assert_eq!(info.allocated + info.free, info.usable);
+        assert_eq!(info.total, 0x40_0000 - 0x1000);
+        assert_eq!(info.bootloader, 0x8_0000);
+        // the partial frames at the edges of the two usable regions are left out
+        let accounted = info.usable + info.bootloader + info.reserved;
+        assert!(accounted <= info.total && info.total - accounted < 2 * 4096);
+        assert_eq!((info.heap_used, info.heap_total, info.kernel_image), (1024, 4096, 0x8000));
+    }
+}
@@ -0,0 +1,68 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use kernel::paging::{map_page, unmap_page};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+use crate::frame_allocator::with_mapper;
+
+/// A range mapped by `map_mmio`
+struct MmioMapping {
+    base: VirtAddr,
+    /// Pages mapped for this range; pages that were already mapped, like the APIC's,
+    /// are left out so `unmap_mmio` keeps them
+    pages: Vec<Page>,
+}
+
+static MAPPINGS: Mutex<Vec<MmioMapping>> = Mutex::new(Vec::new());
+
+/// Identity-maps the device memory at `phys_base..phys_base + size`, uncached and
+/// writable, and returns the virtual address of `phys_base`. The range is widened to
+/// whole pages. Pages already mapped to the same frames are reused.
+#[allow(dead_code)]
+pub fn map_mmio(phys_base: u64, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    let start = PhysAddr::new(phys_base).align_down(4096u64);
+    let end = PhysAddr::new(phys_base + size.max(1)).align_up(4096u64);
+    let mut pages: Vec<Page> = Vec::new();
+    let result = with_mapper(|mapper, frames| {
+        for phys in (start.as_u64()..end.as_u64()).step_by(4096) {
+            let virt = VirtAddr::new(phys);
+            match mapper.translate(virt) {
+                TranslateResult::Mapped { frame, .. } if frame.start_address().as_u64() == phys => continue,
+                _ => {}
+            }
+            if let Err(err) = map_page(mapper, frames, virt, PhysAddr::new(phys), flags) {
+                for page in pages.drain(..) {
+                    unmap_page(mapper, page.start_address()).ok();
+                }
+                return Err(err);
+            }
+            pages.push(Page::containing_address(virt));
+        }
+        Ok(())
+    });
+    result?;
+    let base = VirtAddr::new(phys_base);
+    MAPPINGS.lock().push(MmioMapping { base, pages });
+    Ok(base)
+}
+
+/// Removes a mapping made by `map_mmio`, given the address it returned. Returns
+/// false if there's no such mapping.
+#[allow(dead_code)]
+pub fn unmap_mmio(base: VirtAddr) -> bool {
+    let mapping = {
+        let mut mappings = MAPPINGS.lock();
+        match mappings.iter().position(|mapping| mapping.base == base) {
+            Some(index) => mappings.swap_remove(index),
+            None => return false,
+        }
+    };
+    with_mapper(|mapper, _| {
+        for page in mapping.pages {
+            unmap_page(mapper, page.start_address()).ok();
+        }
+    });
+    true
+}
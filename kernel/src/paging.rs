@@ -0,0 +1,27 @@
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Maps the page containing `virt` to the frame containing `phys` with `flags`, taking
+/// any page tables needed from `frame_allocator`, and flushes the TLB entry. Fails if the
+/// page is already mapped.
+pub fn map_page(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(virt);
+    let frame = PhysFrame::containing_address(phys);
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }?.flush();
+    Ok(())
+}
+
+/// Removes the mapping of the page containing `virt`, flushes the TLB entry and
+/// returns the frame it was mapped to. Page tables left empty are not freed.
+pub fn unmap_page(mapper: &mut impl Mapper<Size4KiB>, virt: VirtAddr) -> Result<PhysFrame, UnmapError> {
+    let (frame, flush) = mapper.unmap(Page::<Size4KiB>::containing_address(virt))?;
+    flush.flush();
+    Ok(frame)
+}
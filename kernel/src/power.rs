@@ -0,0 +1,49 @@
+//! Restarting and powering off the machine.
+//!
+//! Neither needs ACPI tables, which keeps them usable when something has gone badly
+//! wrong, but it also means they only cover common hardware:
+//! - reboot uses the reset control register at port 0xCF9 (Intel chipsets, QEMU), then
+//!   the keyboard controller's reset line (command 0xFE on port 0x64);
+//! - shutdown writes the ACPI sleep-enable bit to the PM1a control ports QEMU (0x604)
+//!   and Bochs/older QEMU (0xB004) use, then tries QEMU's `isa-debug-exit` device on
+//!   port 0xF4, which only exists when QEMU was started with it.
+//!
+//! Real machines usually keep PM1a elsewhere, so on those shutdown just halts.
+
+use x86_64::instructions::port::Port;
+use crate::{hlt_loop, report};
+
+/// Resets the machine; halts if no reset method worked.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::info!("rebooting");
+    unsafe {
+        // full reset: set the reset type first, then trigger it
+        let mut reset_control = Port::<u8>::new(0xCF9);
+        reset_control.write(0x02);
+        reset_control.write(0x06);
+
+        // pulse the CPU reset line through the keyboard controller, once its input
+        // buffer is empty
+        let mut status = Port::<u8>::new(0x64);
+        while status.read() & 0x02 != 0 {}
+        status.write(0xFE);
+    }
+    report(format_args!("reboot failed, halting\n"));
+    hlt_loop();
+}
+
+/// Turns the machine off; halts if no power off method worked.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    crate::info!("shutting down");
+    unsafe {
+        // SLP_EN with sleep type 0, which is S5 (soft off) on QEMU and Bochs
+        Port::<u16>::new(0x604).write(0x2000);
+        Port::<u16>::new(0xB004).write(0x2000);
+        // isa-debug-exit: QEMU exits with status (value << 1) | 1
+        Port::<u32>::new(0xF4).write(0);
+    }
+    report(format_args!("shutdown failed, halting\n"));
+    hlt_loop();
+}
@@ -0,0 +1,90 @@
+use core::fmt;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+/// Wall-clock time as kept by the CMOS real-time clock, usually local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/// CMOS registers, selected through port 0x70 and read through port 0x71
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        // bit 7 of the index port disables NMIs, which is how it is left
+        Port::<u8>::new(0x70).write(0x80 | register);
+        Port::<u8>::new(0x71).read()
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(STATUS_A) & 0x80 != 0
+}
+
+fn read_raw() -> [u8; 6] {
+    while update_in_progress() {}
+    [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(read_register)
+}
+
+/// Reads the current date and time from the RTC. The century register isn't at a
+/// fixed place (the FADT says where, if anywhere), so years are taken to be 20xx.
+pub fn now() -> DateTime {
+    let (raw, status_b) = without_interrupts(|| {
+        // the clock may tick over while being read; go again until two reads agree
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(STATUS_B))
+    });
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year] = raw;
+
+    // bit 2 of status B: values are binary rather than BCD
+    if status_b & 0x04 == 0 {
+        let from_bcd = |value: u8| (value & 0x0F) + (value >> 4) * 10;
+        second = from_bcd(second);
+        minute = from_bcd(minute);
+        // the 12-hour PM flag sits above the BCD digits
+        hour = from_bcd(hour & 0x7F) | (hour & 0x80);
+        day = from_bcd(day);
+        month = from_bcd(month);
+        year = from_bcd(year);
+    }
+    // bit 1 of status B: 24-hour mode; otherwise bit 7 of the hour means PM
+    if status_b & 0x02 == 0 {
+        let pm = hour & 0x80 != 0;
+        hour = match (hour & 0x7F, pm) {
+            (12, false) => 0,
+            (12, true) => 12,
+            (h, true) => h + 12,
+            (h, false) => h,
+        };
+    }
+
+    DateTime { year: 2000 + u16::from(year), month, day, hour, minute, second }
+}
@@ -1,70 +1,662 @@
 // Original code from rust-osdev/bootloader crate https://github.com/rust-osdev/bootloader
 
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::{fmt, ptr};
-use noto_sans_mono_bitmap::{FontWeight, get_raster, RasterizedChar};
+use core::ops::{Deref, DerefMut, Range};
+use noto_sans_mono_bitmap::{FontWeight, get_raster, get_raster_width, RasterizedChar};
 use bootloader_api::info::{FrameBuffer, FrameBufferInfo, PixelFormat};
 use noto_sans_mono_bitmap::RasterHeight::Size16;
-use kernel::RacyCell;
+use core::fmt::Write;
+use kernel::{ArrayString, serial};
+use kernel::sync::{IrqMutex, IrqMutexGuard};
 
-static WRITER: RacyCell<Option<ScreenWriter>> = RacyCell::new(None);
+static WRITER: IrqMutex<Option<ScreenWriter>> = IrqMutex::new(None);
+
+/// Writes to the screen, or to serial when booted without a framebuffer.
 pub struct Writer;
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        let writer = unsafe { WRITER.get_mut() }.as_mut().unwrap();
-        writer.write_str(s)
+        match screenwriter() {
+            Some(mut writer) => writer.write_str(s),
+            None => serial().write_str(s),
+        }
     }
 }
 
-pub fn screenwriter() -> &'static mut ScreenWriter {
-    let writer = unsafe { WRITER.get_mut() }.as_mut().unwrap();
-    writer
+/// The screen, locked by `screenwriter`. Interrupts stay off until it's dropped, so
+/// nothing draws in between: not the timer, not another task. Drop it before writing
+/// with `Writer`, which locks the screen itself.
+pub struct ScreenGuard(IrqMutexGuard<'static, Option<ScreenWriter>>);
+
+impl Deref for ScreenGuard {
+    type Target = ScreenWriter;
+
+    fn deref(&self) -> &ScreenWriter {
+        self.0.as_ref().expect("screen guard without a screen")
+    }
 }
 
+impl DerefMut for ScreenGuard {
+    fn deref_mut(&mut self) -> &mut ScreenWriter {
+        self.0.as_mut().expect("screen guard without a screen")
+    }
+}
+
+/// Locks the screen, or returns `None` if `init` wasn't called because there is no
+/// framebuffer.
+pub fn screenwriter() -> Option<ScreenGuard> {
+    let guard = WRITER.lock();
+    guard.is_some().then(|| ScreenGuard(guard))
+}
+
+/// Like `screenwriter`, but also `None` while the screen is locked, for interrupt
+/// handlers that can leave drawing for later.
+pub fn try_screenwriter() -> Option<ScreenGuard> {
+    let guard = WRITER.try_lock()?;
+    guard.is_some().then(|| ScreenGuard(guard))
+}
+
+/// Erases all text, on the screen or on a terminal on serial.
+pub fn clear() {
+    match screenwriter() {
+        Some(mut writer) => writer.clear(),
+        None => { serial().write_str("\x1b[2J\x1b[H").ok(); }
+    }
+}
+
+/// Blanks the current row from column `col` on, as `ScreenWriter::clear_row_from`.
+/// Without a screen, a terminal on serial is told to erase the rest of the line, which
+/// assumes its cursor is at `col`.
+pub fn clear_row_from(col: usize) {
+    match screenwriter() {
+        Some(mut writer) => writer.clear_row_from(col),
+        None => { serial().write_str("\x1b[K").ok(); }
+    }
+}
+
+/// Moves the write position to column `col` of the current row, on the screen or on a
+/// terminal on serial.
+pub fn move_to_column(col: usize) {
+    match screenwriter() {
+        Some(mut writer) => writer.move_to_column(col),
+        None if col == 0 => { serial().write_str("\r").ok(); }
+        None => { write!(serial(), "\r\x1b[{}C", col).ok(); }
+    }
+}
+
+/// Prints a fault report on screen in `FAULT_COLOR`; installed with
+/// `kernel::set_console`. Reports already go to serial, so they aren't mirrored there
+/// again, and they don't allocate: the allocator may be what failed. A fault in the
+/// middle of drawing leaves the screen locked, and the report on serial only.
+pub fn console(args: fmt::Arguments) {
+    let Some(mut writer) = try_screenwriter() else { return };
+    let mirror = core::mem::replace(&mut writer.mirror_serial, false);
+    let color = core::mem::replace(&mut writer.color, FAULT_COLOR);
+    writer.fatal = true;
+    if writer.x_pos != 0 {
+        writer.newline();
+    }
+    writer.write_fmt(args).ok();
+    writer.fatal = false;
+    writer.color = color;
+    writer.mirror_serial = mirror;
+}
+
+/// Makes everything written to the screen also go out over serial, without escape
+/// sequences.
+pub fn set_mirror_serial(on: bool) {
+    if let Some(mut writer) = screenwriter() {
+        writer.mirror_serial = on;
+    }
+}
 
 pub fn init(buffer: &'static mut FrameBuffer) {
     let info = buffer.info();
     let framebuffer = buffer.buffer_mut();
-    let writer = ScreenWriter::new(framebuffer, info);
-    *unsafe { WRITER.get_mut() } = Some(writer);
+    let mut writer = ScreenWriter::new(framebuffer, info);
+    benchmark_clear(&mut writer);
+    *WRITER.lock() = Some(writer);
+}
+
+/// Logs over serial how long a full-screen clear takes when writing straight to the
+/// framebuffer compared to going through the back buffer.
+fn benchmark_clear(writer: &mut ScreenWriter) {
+    let rdtsc = kernel::time::rdtsc;
+    let start = rdtsc();
+    writer.framebuffer.fill(0);
+    let direct = rdtsc() - start;
+    let start = rdtsc();
+    writer.clear_buffer();
+    let buffered = rdtsc() - start;
+    writer.present();
+    let presented = rdtsc() - start;
+    kernel::debug!("screen clear: {} cycles direct, {} cycles into back buffer, {} cycles including present",
+        direct, buffered, presented);
 }
 
 /// Additional vertical space between lines
 const LINE_SPACING: usize = 0;
+const LINE_HEIGHT: usize = Size16 as usize + LINE_SPACING;
+const CHAR_WIDTH: usize = get_raster_width(FontWeight::Regular, Size16);
+
+/// Columns between tab stops
+const TAB_WIDTH: usize = 8;
+
+/// Number of text rows kept for scrollback, including the ones currently on screen
+const SCROLLBACK_ROWS: usize = 200;
+
+/// Most parameters kept from a single escape sequence; extra ones are ignored
+const MAX_ESCAPE_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Parses one of the eight basic color names (`black`, `red`, ... `white`, as the
+    /// ANSI colors) or `#RRGGBB`.
+    pub fn parse(text: &str) -> Option<Self> {
+        if let Some(hex) = text.strip_prefix('#') {
+            if hex.len() != 6 || !hex.is_ascii() {
+                return None;
+            }
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            return Some(Self::new(channel(0)?, channel(2)?, channel(4)?));
+        }
+        ANSI_COLOR_NAMES.iter().position(|&name| name.eq_ignore_ascii_case(text)).map(|i| ANSI_COLORS[i])
+    }
+}
+
+/// Text color used until an escape sequence changes it
+pub const DEFAULT_COLOR: Color = Color::new(0x40, 0xff, 0x80);
+
+/// Longest status bar text, in bytes
+const STATUS_LEN: usize = 256;
+
+/// Colors of the status bar
+const STATUS_COLOR: Color = Color::new(0xff, 0xff, 0xff);
+const STATUS_BACKGROUND: Color = Color::new(0x20, 0x40, 0x80);
+
+/// Text color of fault reports and panics
+const FAULT_COLOR: Color = Color::new(0xff, 0x50, 0x50);
+
+/// Colors selected by the ANSI SGR codes 30 to 37
+const ANSI_COLORS: [Color; 8] = [
+    Color::new(0x00, 0x00, 0x00), // black
+    Color::new(0xcd, 0x00, 0x00), // red
+    Color::new(0x00, 0xcd, 0x00), // green
+    Color::new(0xcd, 0xcd, 0x00), // yellow
+    Color::new(0x5c, 0x5c, 0xff), // blue
+    Color::new(0xcd, 0x00, 0xcd), // magenta
+    Color::new(0x00, 0xcd, 0xcd), // cyan
+    Color::new(0xe5, 0xe5, 0xe5), // white
+];
+
+/// Names of `ANSI_COLORS`, for `Color::parse`
+const ANSI_COLOR_NAMES: [&str; 8] = ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+/// Byte order used to store a pixel, derived from the framebuffer's `PixelFormat`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PixelLayout {
+    Rgb,
+    Bgr,
+    /// One grayscale byte per pixel
+    Gray,
+}
+
+impl PixelLayout {
+    fn from_format(format: PixelFormat) -> Self {
+        match format {
+            PixelFormat::Rgb => PixelLayout::Rgb,
+            PixelFormat::Bgr => PixelLayout::Bgr,
+            PixelFormat::U8 => PixelLayout::Gray,
+            other => {
+                kernel::warn!("pixel format {:?} not supported, assuming BGR", other);
+                PixelLayout::Bgr
+            }
+        }
+    }
+}
+
+/// Scrollback row written to, see `ScreenWriter::set_position`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Row {
+    /// The live tail, the last row
+    Tail,
+    /// The row at this index
+    At(usize),
+    /// None: the write position is on a row without scrollback
+    Off,
+}
+
+/// Progress through an escape sequence, which may span several `write_str` calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    /// Got ESC, waiting for the next character
+    Start,
+    /// Inside a control sequence (`ESC [ ...`), waiting for its final character
+    Csi,
+}
+
+/// Pixels of a rectangle of the screen, saved by `ScreenWriter::save_region` to be put
+/// back with `restore_region`
+pub struct RegionSnapshot {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+    /// `h` rows of `w` pixels, in the framebuffer's format
+    pixels: Vec<u8>,
+}
 
 pub struct ScreenWriter {
     framebuffer: &'static mut [u8],
+    /// Off-screen copy of the framebuffer that all drawing goes to; `None` if there
+    /// wasn't enough memory, in which case drawing goes straight to the framebuffer.
+    back: Option<Vec<u8>>,
+    /// Range of pixel rows (inclusive) changed in the back buffer since the last `present()`
+    dirty: Option<(usize, usize)>,
     info: FrameBufferInfo,
+    layout: PixelLayout,
     x_pos: usize,
     y_pos: usize,
+    /// Characters of the most recent rows, oldest first; the last one is being written to.
+    rows: VecDeque<Vec<(char, Color)>>,
+    /// How many rows the view is scrolled back from the live tail.
+    scroll: usize,
+    color: Color,
+    /// What the screen is cleared to, and what glyphs are blended onto
+    background: Color,
+    /// Size of each font pixel on screen, in pixels across and down
+    scale: usize,
+    /// Whether the blinking cursor is enabled at all
+    cursor_visible: bool,
+    /// Whether the cursor block is currently drawn over the cell at (x_pos, y_pos)
+    cursor_drawn: bool,
+    /// Whether text is copied to the serial port
+    mirror_serial: bool,
+    /// Whether the bottom row is kept for the status bar
+    status_bar: bool,
+    /// Text of the status bar
+    status: ArrayString<STATUS_LEN>,
+    /// Whether a newline at the bottom copies rows up instead of redrawing everything
+    fast_scroll: bool,
+    /// Text rows (top, bottom, inclusive) that scroll, set by `scroll_region`; `None`
+    /// for all of them
+    region: Option<(usize, usize)>,
+    /// Where text goes in the scrollback; anything but `Row::Tail` after `set_position`
+    row: Row,
+    /// Write position at the live tail, kept while `set_position` has moved away from it
+    tail_pos: (usize, usize),
+    /// Set while `console` writes a fault report: nothing goes into the scrollback, and
+    /// instead of scrolling, rows wrap around to the top of the screen
+    fatal: bool,
+    escape: Escape,
+    escape_params: [u16; MAX_ESCAPE_PARAMS],
+    escape_len: usize,
 }
 
 impl ScreenWriter {
     pub fn new(framebuffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+        let mut back = Vec::new();
+        let back = if back.try_reserve_exact(framebuffer.len()).is_ok() {
+            back.resize(framebuffer.len(), 0);
+            Some(back)
+        } else {
+            kernel::warn!("no memory for a screen back buffer, drawing directly");
+            None
+        };
         let mut logger = Self {
             framebuffer,
+            back,
+            dirty: None,
             info,
+            layout: PixelLayout::from_format(info.pixel_format),
             x_pos: 0,
             y_pos: 0,
+            rows: VecDeque::new(),
+            scroll: 0,
+            color: DEFAULT_COLOR,
+            background: Color::new(0, 0, 0),
+            scale: 1,
+            cursor_visible: true,
+            cursor_drawn: false,
+            mirror_serial: false,
+            fatal: false,
+            status_bar: true,
+            status: ArrayString::new(),
+            fast_scroll: true,
+            region: None,
+            row: Row::Tail,
+            tail_pos: (0, 0),
+            escape: Escape::None,
+            escape_params: [0; MAX_ESCAPE_PARAMS],
+            escape_len: 0,
         };
         logger.clear();
         logger
     }
 
     fn newline(&mut self) {
-        self.y_pos += Size16 as usize + LINE_SPACING;
-        self.carriage_return()
+        self.carriage_return();
+        if self.fatal {
+            self.y_pos += self.line_height();
+            if self.y_pos + self.line_height() > self.height() {
+                self.y_pos = 0;
+            }
+            self.draw_rect(0, self.y_pos, self.width(), self.line_height(), self.background, true);
+            return;
+        }
+        if let Row::At(_) | Row::Off = self.row {
+            // positioned text wraps to the row below, and stays on the last one
+            if self.y_pos + 2 * self.line_height() <= self.text_height() {
+                self.y_pos += self.line_height();
+            }
+            self.row = match self.row {
+                Row::At(i) if i + 1 < self.rows.len() => Row::At(i + 1),
+                _ => Row::Off,
+            };
+            return;
+        }
+        self.push_row();
+        let (_, bottom) = self.region();
+        if self.y_pos + 2 * self.line_height() > (bottom + 1) * self.line_height() {
+            // no room for another row: scroll the region up by one
+            if self.fast_scroll {
+                self.shift_up();
+            } else {
+                self.redraw();
+            }
+        } else {
+            self.y_pos += self.line_height();
+        }
     }
 
+    /// Moves the pixels of the scroll region up one text row in a single copy and blanks
+    /// the row that comes free at its bottom, leaving the rest of the screen alone. What
+    /// `redraw` would show at the live tail, without repainting every glyph.
+    fn shift_up(&mut self) {
+        let (top, bottom) = self.region();
+        let line_height = self.line_height();
+        let (first, end) = (top * line_height, (bottom + 1) * line_height);
+        if bottom > top {
+            let from = self.byte_offset(0, first + line_height);
+            let to = self.byte_offset(0, first);
+            let len = self.byte_offset(0, end) - from;
+            self.pixels().copy_within(from..from + len, to);
+        }
+        self.fill_rows(end - line_height..end, self.background);
+        self.dirty = Some(match self.dirty {
+            Some((dirty_top, dirty_bottom)) => (dirty_top.min(first), dirty_bottom.max(end - 1)),
+            None => (first, end - 1),
+        });
+        self.cursor_drawn = false;
+    }
+
+    /// Sets the pixel rows `rows` to `color`, across the whole stride.
+    fn fill_rows(&mut self, rows: Range<usize>, color: Color) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let pixel = self.encode(color);
+        let (start, end) = (self.byte_offset(0, rows.start), self.byte_offset(0, rows.end));
+        let end = end.min(self.pixels().len());
+        for chunk in self.pixels()[start..end].chunks_exact_mut(bytes_per_pixel) {
+            chunk.copy_from_slice(&pixel[..bytes_per_pixel]);
+        }
+    }
+
+    /// Whether scrolling at the live tail copies rows (the default) or repaints the
+    /// screen from the scrollback; `bench scroll` compares the two.
+    pub fn set_fast_scroll(&mut self, on: bool) {
+        self.fast_scroll = on;
+    }
+
+    /// Back to column 0 of the same row. What is written next replaces the row's
+    /// characters one by one, on screen and in the scrollback; the rest of the row stays,
+    /// so writing `"abc\rX"` leaves `Xbc`.
     fn carriage_return(&mut self) {
         self.x_pos = 0;
     }
 
+    /// Back one cell on the same row, like a terminal's BS; at column 0 it stays put.
+    /// Nothing is erased, so the character there is replaced by what's written next, as
+    /// after `carriage_return`: `"\x08 \x08"` rubs out the character before the cursor.
+    fn backspace(&mut self) {
+        self.x_pos = self.x_pos.saturating_sub(self.char_width());
+    }
+
     /// Erases all text on the screen.
     pub fn clear(&mut self) {
+        self.row = Row::Tail;
         self.x_pos = 0;
-        self.y_pos = 0;
-        self.framebuffer.fill(0);
+        self.y_pos = self.region().0 * self.line_height();
+        self.clear_buffer();
+        self.rows.clear();
+        self.rows.push_back(Vec::new());
+        self.scroll = 0;
+        self.cursor_drawn = false;
+        self.draw_status();
+        self.present();
+    }
+
+    /// Blanks the current row from column `col` to its end, both on screen and in the
+    /// scrollback. The write position is left where it is.
+    pub fn clear_row_from(&mut self, col: usize) {
+        self.hide_cursor();
+        let x = col * self.char_width();
+        let width = self.width().saturating_sub(x);
+        self.draw_rect(x, self.y_pos, width, self.line_height(), self.background, true);
+        if let Some(row) = self.current_row() {
+            row.truncate(col);
+        }
+        self.present();
+    }
+
+    /// Moves the write position to cell (`col`, `row`) of the screen, clamped to the
+    /// last cell, so that text can be placed anywhere. Until `reset_position`, newlines
+    /// go to the start of the next screen row without scrolling, and the text only goes
+    /// into the scrollback on rows that have some; on the blank rows below the live tail
+    /// it's gone with the next scroll.
+    pub fn set_position(&mut self, col: usize, row: usize) {
+        self.hide_cursor();
+        if self.row == Row::Tail {
+            self.tail_pos = (self.x_pos, self.y_pos);
+        }
+        let (_, _, cols, rows) = self.dimensions();
+        let (col, row) = (col.min(cols - 1), row.min(rows - 1));
+        let tail_row = self.tail_pos.1 / self.line_height();
+        let (top, _) = self.region();
+        // the live tail is the last entry of `rows`, and the ones above it are before it;
+        // rows above the scroll region have none
+        self.row = match (tail_row.checked_sub(row), self.scroll) {
+            (Some(up), 0) if row >= top && up < self.rows.len() => Row::At(self.rows.len() - 1 - up),
+            _ => Row::Off,
+        };
+        self.x_pos = col * self.char_width();
+        self.y_pos = row * self.line_height();
+    }
+
+    /// Returns the write position to the live tail after `set_position`.
+    pub fn reset_position(&mut self) {
+        if self.row != Row::Tail {
+            self.hide_cursor();
+            self.row = Row::Tail;
+            (self.x_pos, self.y_pos) = self.tail_pos;
+        }
+    }
+
+    /// Writes `args` starting at cell (`col`, `row`) as `set_position` does, then puts
+    /// the write position back where it was.
+    pub fn write_at(&mut self, col: usize, row: usize, args: fmt::Arguments) {
+        let saved = (self.x_pos, self.y_pos, self.row, self.tail_pos);
+        self.set_position(col, row);
+        self.write_fmt(args).ok();
+        self.hide_cursor();
+        (self.x_pos, self.y_pos, self.row, self.tail_pos) = saved;
+    }
+
+    /// Characters of the scrollback row `back` rows above the live tail, which is 0, or
+    /// `None` if the scrollback doesn't reach that far.
+    pub fn row_text(&self, back: usize) -> Option<String> {
+        let row = self.rows.get(self.rows.len().checked_sub(back + 1)?)?;
+        Some(row.iter().map(|&(c, _)| c).collect())
+    }
+
+    /// Scrollback row that text at the write position goes into.
+    fn current_row(&mut self) -> Option<&mut Vec<(char, Color)>> {
+        match self.row {
+            Row::Tail => self.rows.back_mut(),
+            Row::At(i) => self.rows.get_mut(i),
+            Row::Off => None,
+        }
+    }
+
+    /// Moves the write position to column `col` of the current row.
+    pub fn move_to_column(&mut self, col: usize) {
+        self.hide_cursor();
+        self.x_pos = (col * self.char_width()).min(self.width());
+    }
+
+    /// Erases all text and makes `color` the background from now on, for new rows and
+    /// everything drawn after scrolling too.
+    pub fn clear_to_color(&mut self, color: Color) {
+        self.background = color;
+        self.clear();
+    }
+
+    /// Sets every pixel (of the back buffer, if there is one) to the background color.
+    fn clear_buffer(&mut self) {
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        let pixel = self.encode(self.background);
+        if pixel[..bytes_per_pixel].iter().all(|&byte| byte == 0) {
+            self.pixels().fill(0);
+        } else {
+            for chunk in self.pixels().chunks_exact_mut(bytes_per_pixel) {
+                chunk.copy_from_slice(&pixel[..bytes_per_pixel]);
+            }
+        }
+        self.dirty = Some((0, self.height().saturating_sub(1)));
+    }
+
+    /// Bytes all drawing goes to.
+    fn pixels(&mut self) -> &mut [u8] {
+        match &mut self.back {
+            Some(back) => back,
+            None => self.framebuffer,
+        }
+    }
+
+    /// Copies the rows changed since the last call from the back buffer to the framebuffer.
+    /// Writing text presents automatically; callers drawing with `draw_pixel` must call this.
+    pub fn present(&mut self) {
+        let Some((top, bottom)) = self.dirty.take() else { return };
+        if let Some(back) = &self.back {
+            let start = self.byte_offset(0, top);
+            let end = self.byte_offset(0, bottom + 1).min(back.len());
+            self.framebuffer[start..end].copy_from_slice(&back[start..end]);
+        }
+    }
+
+    /// Enables or disables the blinking cursor.
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.cursor_visible = visible;
+        if !visible {
+            self.hide_cursor();
+        }
+    }
+
+    /// Toggles the cursor block at the current write position; called periodically
+    /// from the timer to make it blink.
+    pub fn blink_cursor(&mut self) {
+        if self.cursor_drawn {
+            self.hide_cursor();
+        } else if self.cursor_visible && self.scroll == 0 && self.x_pos + self.char_width() <= self.width() {
+            self.draw_rect(self.x_pos, self.y_pos, self.char_width(), self.line_height(), self.color, true);
+            self.cursor_drawn = true;
+        }
+        self.present();
+    }
+
+    /// Removes the cursor block, restoring whatever character was underneath it.
+    fn hide_cursor(&mut self) {
+        if !self.cursor_drawn {
+            return;
+        }
+        self.cursor_drawn = false;
+        self.draw_rect(self.x_pos, self.y_pos, self.char_width(), self.line_height(), self.background, true);
+        let col = self.x_pos / self.char_width();
+        let cell = self.current_row().and_then(|row| row.get(col)).copied();
+        if let Some((c, color)) = cell {
+            if let Some(bitmap_char) = get_raster(c, FontWeight::Regular, Size16) {
+                self.draw_rendered_char(self.x_pos, self.y_pos, bitmap_char, color);
+            }
+        }
+    }
+
+
+    /// Scrolls the view back by `lines` rows, as far as the scrollback reaches.
+    pub fn scroll_up(&mut self, lines: usize) {
+        let max = self.rows.len().saturating_sub(self.region_rows());
+        let scroll = (self.scroll + lines).min(max);
+        if scroll != self.scroll {
+            self.scroll = scroll;
+            self.redraw();
+            self.present();
+        }
+    }
+
+    /// Scrolls the view forward by `lines` rows, stopping at the live tail.
+    pub fn scroll_down(&mut self, lines: usize) {
+        let scroll = self.scroll.saturating_sub(lines);
+        if scroll != self.scroll {
+            self.scroll = scroll;
+            self.redraw();
+            self.present();
+        }
+    }
+
+    /// Sets the font scale: 1 for the normal font, 2 to draw every font pixel as a
+    /// 2x2 block. The visible rows are repainted at the new size.
+    pub fn set_scale(&mut self, scale: usize) {
+        let scale = scale.clamp(1, 2);
+        if scale == self.scale {
+            return;
+        }
+        self.hide_cursor();
+        self.reset_position();
+        self.scale = scale;
+        self.scroll = 0;
+        self.redraw();
+        // past the right edge, the next character wraps to a new row
+        let col = self.rows.back().map_or(0, |row| row.len());
+        self.x_pos = (col * self.char_width()).min(self.width());
+        self.present();
+    }
+
+    /// Returns `(width_px, height_px, cols, rows)`: the framebuffer size and how many
+    /// character cells fit on it at the current font scale.
+    pub fn dimensions(&self) -> (usize, usize, usize, usize) {
+        (self.width(), self.height(), self.width() / self.char_width(), self.text_rows())
+    }
+
+    /// Width of a character cell at the current scale.
+    fn char_width(&self) -> usize {
+        CHAR_WIDTH * self.scale
+    }
+
+    /// Height of a text row at the current scale.
+    fn line_height(&self) -> usize {
+        LINE_HEIGHT * self.scale
     }
 
     fn width(&self) -> usize {
@@ -75,19 +667,280 @@ impl ScreenWriter {
         self.info.height.into()
     }
 
+    /// Number of text rows that fit on the screen, less the status bar's.
+    fn text_rows(&self) -> usize {
+        let rows = (self.height() / self.line_height()).max(1);
+        if self.status_bar && rows > 1 { rows - 1 } else { rows }
+    }
+
+    /// Height in pixels of the rows text is written to.
+    fn text_height(&self) -> usize {
+        self.text_rows() * self.line_height()
+    }
+
+    /// Confines scrolling to text rows `top` to `bottom` (inclusive): text runs inside
+    /// them, and the rows above and below stay as they are, for `write_at` to fill in.
+    /// A region covering every row, like `scroll_region(0, usize::MAX)`, scrolls the
+    /// whole screen again. The region is repainted from the scrollback.
+    pub fn scroll_region(&mut self, top: usize, bottom: usize) {
+        let last = self.text_rows() - 1;
+        let (top, bottom) = (top.min(last), bottom.clamp(top.min(last), last));
+        self.hide_cursor();
+        self.reset_position();
+        self.region = if top == 0 && bottom == last { None } else { Some((top, bottom)) };
+        self.scroll = 0;
+        self.redraw();
+        self.present();
+    }
+
+    /// Text rows (top, bottom, inclusive) that scroll. Rows the screen lost since
+    /// `scroll_region`, to a bigger font or the status bar, are dropped from the region.
+    fn region(&self) -> (usize, usize) {
+        let last = self.text_rows() - 1;
+        self.region.map_or((0, last), |(top, bottom)| (top.min(last), bottom.min(last)))
+    }
+
+    /// Number of rows in the scroll region.
+    fn region_rows(&self) -> usize {
+        let (top, bottom) = self.region();
+        bottom - top + 1
+    }
+
+    /// Reserves the bottom row for the status bar, or gives it back to the text.
+    pub fn set_status_bar(&mut self, on: bool) {
+        if on == self.status_bar {
+            return;
+        }
+        self.hide_cursor();
+        self.reset_position();
+        self.status_bar = on;
+        self.scroll = 0;
+        self.redraw();
+        self.present();
+    }
+
+    /// Draws the status bar row, if it's on, with `status` in it.
+    fn draw_status(&mut self) {
+        if !self.status_bar {
+            return;
+        }
+        let y = self.text_height();
+        let (width, height) = (self.width(), self.line_height());
+        self.draw_rect(0, y, width, height, STATUS_BACKGROUND, true);
+        let status = self.status;
+        self.draw_text(0, y, status.as_str(), STATUS_COLOR, STATUS_BACKGROUND);
+    }
+
+    /// Whether the bottom row is kept for the status bar.
+    pub fn has_status_bar(&self) -> bool {
+        self.status_bar
+    }
+
+    /// Shows `args` in the status bar, if it's on. Formats into a fixed buffer, so the
+    /// timer can call it without allocating.
+    pub fn set_status(&mut self, args: fmt::Arguments) {
+        self.status.clear();
+        self.status.write_fmt(args).ok();
+        self.draw_status();
+        self.present();
+    }
+
+    /// Draws `text` on one line from (x, y), in `color` on `background`, without moving
+    /// the write position or touching the scrollback. Stops at the right edge.
+    pub fn draw_text(&mut self, x: usize, y: usize, text: &str, color: Color, background: Color) {
+        // glyphs are blended onto the background, so it has to be this one for now
+        let background = core::mem::replace(&mut self.background, background);
+        let cols = self.width().saturating_sub(x) / self.char_width();
+        for (col, c) in text.chars().take(cols).enumerate() {
+            if let Some(bitmap_char) = get_raster(c, FontWeight::Regular, Size16) {
+                self.draw_rendered_char(x + col * self.char_width(), y, bitmap_char, color);
+            }
+        }
+        self.background = background;
+    }
+
+    /// Copies the pixels of the `w` x `h` rectangle at (x, y), clipped to the screen, so
+    /// they can be drawn over and put back with `restore_region`.
+    pub fn save_region(&mut self, x: usize, y: usize, w: usize, h: usize) -> RegionSnapshot {
+        let x = x.min(self.width());
+        let y = y.min(self.height());
+        let w = w.min(self.width() - x);
+        let h = h.min(self.height() - y);
+        let row_bytes = w * self.info.bytes_per_pixel;
+        let mut pixels = Vec::with_capacity(row_bytes * h);
+        for row in y..y + h {
+            let start = self.byte_offset(x, row);
+            pixels.extend_from_slice(&self.pixels()[start..start + row_bytes]);
+        }
+        RegionSnapshot { x, y, w, h, pixels }
+    }
+
+    /// Puts back the pixels saved by `save_region`, exactly as they were.
+    pub fn restore_region(&mut self, snapshot: RegionSnapshot) {
+        let RegionSnapshot { x, y, w, h, pixels } = snapshot;
+        if w == 0 || h == 0 {
+            return;
+        }
+        let row_bytes = w * self.info.bytes_per_pixel;
+        for (row, saved) in (y..y + h).zip(pixels.chunks_exact(row_bytes)) {
+            let start = self.byte_offset(x, row);
+            self.pixels()[start..start + row_bytes].copy_from_slice(saved);
+        }
+        let bottom = y + h - 1;
+        self.dirty = Some(match self.dirty {
+            Some((top, end)) => (top.min(y), end.max(bottom)),
+            None => (y, bottom),
+        });
+        self.present();
+    }
+
+    /// Draws `text` in a bordered box in the middle of the screen, returning what was
+    /// under the box to put back with `restore_region`.
+    pub fn message_box(&mut self, text: &str) -> RegionSnapshot {
+        self.hide_cursor();
+        let (char_width, line_height) = (self.char_width(), self.line_height());
+        let cols = text.chars().count().min((self.width() / char_width).saturating_sub(4));
+        let text = &text[..text.char_indices().nth(cols).map_or(text.len(), |(i, _)| i)];
+        let (w, h) = ((cols + 4) * char_width, 3 * line_height);
+        let x = self.width().saturating_sub(w) / 2;
+        let y = self.height().saturating_sub(h) / 2;
+        let snapshot = self.save_region(x, y, w, h);
+        self.draw_rect(x, y, w, h, STATUS_BACKGROUND, true);
+        self.draw_rect(x + char_width / 2, y + line_height / 2, w - char_width, h - line_height, STATUS_COLOR, false);
+        self.draw_text(x + 2 * char_width, y + line_height, text, STATUS_COLOR, STATUS_BACKGROUND);
+        self.present();
+        snapshot
+    }
+
+    fn push_row(&mut self) {
+        self.rows.push_back(Vec::new());
+        if self.rows.len() > SCROLLBACK_ROWS {
+            self.rows.pop_front();
+        }
+    }
+
+    /// Records `c` in the scrollback at the current cursor cell.
+    fn remember_char(&mut self, c: char) {
+        if self.fatal {
+            return;
+        }
+        let col = self.x_pos / self.char_width();
+        let color = self.color;
+        if let Some(row) = self.current_row() {
+            if row.len() <= col {
+                row.resize(col + 1, (' ', color));
+            }
+            row[col] = (c, color);
+        }
+    }
+
+    /// Feeds one character of an escape sequence. Only SGR foreground colors (30-37),
+    /// default (39) and reset (0) are acted upon; anything else is swallowed silently.
+    fn handle_escape(&mut self, c: char) {
+        match (self.escape, c) {
+            (Escape::Start, '[') => {
+                self.escape = Escape::Csi;
+                self.escape_params = [0; MAX_ESCAPE_PARAMS];
+                self.escape_len = 0;
+            }
+            (Escape::Start, _) => self.escape = Escape::None,
+            (Escape::Csi, '0'..='9') => {
+                if self.escape_len == 0 {
+                    self.escape_len = 1;
+                }
+                if let Some(param) = self.escape_params.get_mut(self.escape_len - 1) {
+                    *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+            }
+            (Escape::Csi, ';') => self.escape_len = (self.escape_len.max(1) + 1).min(MAX_ESCAPE_PARAMS + 1),
+            (Escape::Csi, '\x40'..='\x7e') => {
+                if c == 'm' {
+                    self.apply_sgr();
+                }
+                self.escape = Escape::None;
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_sgr(&mut self) {
+        // `ESC [ m` is the same as `ESC [ 0 m`
+        let len = self.escape_len.clamp(1, MAX_ESCAPE_PARAMS);
+        for i in 0..len {
+            match self.escape_params[i] {
+                0 | 39 => self.color = DEFAULT_COLOR,
+                code @ 30..=37 => self.color = ANSI_COLORS[usize::from(code - 30)],
+                _ => {}
+            }
+        }
+    }
+
+    /// Repaints the screen from the scrollback, showing the rows that end `scroll` rows
+    /// before the live tail.
+    fn redraw(&mut self) {
+        let (top, bottom) = self.region();
+        if self.region.is_some() {
+            // only the region moves, the rows around it stay
+            let (width, line_height) = (self.width(), self.line_height());
+            self.draw_rect(0, top * line_height, width, (bottom - top + 1) * line_height, self.background, true);
+        } else {
+            self.clear_buffer();
+        }
+        self.cursor_drawn = false;
+        let rows = core::mem::take(&mut self.rows);
+        let end = rows.len() - self.scroll;
+        let start = end.saturating_sub(bottom - top + 1);
+        // rows written at a smaller scale may be wider than the screen; cut them off
+        let cols = self.width() / self.char_width();
+        for (i, row) in rows.range(start..end).enumerate() {
+            for (col, (c, color)) in row.iter().enumerate().take(cols) {
+                if let Some(bitmap_char) = get_raster(*c, FontWeight::Regular, Size16) {
+                    self.draw_rendered_char(col * self.char_width(), (top + i) * self.line_height(), bitmap_char, *color);
+                }
+            }
+        }
+        if self.scroll == 0 {
+            // the rows moved, so whatever `set_position` picked is stale
+            if self.row != Row::Tail {
+                self.row = Row::Tail;
+                self.x_pos = self.tail_pos.0;
+            }
+            self.y_pos = (top + end - start - 1) * self.line_height();
+        }
+        self.rows = rows;
+        self.draw_status();
+    }
+
     fn write_char(&mut self, c: char) {
+        self.hide_cursor();
+        if self.scroll != 0 {
+            // new output always shows up at the live tail
+            self.scroll = 0;
+            self.redraw();
+        }
+        if self.escape != Escape::None {
+            self.handle_escape(c);
+            return;
+        }
+        if self.mirror_serial && c != '\x1b' {
+            // straight to the port, which never writes back to the screen
+            serial().write_char(c).ok();
+        }
         match c {
+            '\x1b' => self.escape = Escape::Start,
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
+            '\x08' => self.backspace(),
+            '\t' => self.tab(),
             c => {
                 match get_raster(c, FontWeight::Regular, Size16) {
                     Some(bitmap_char) => {
-                        if self.x_pos + bitmap_char.width() > self.width() {
+                        // wrap before a character that wouldn't fit, so nothing is
+                        // drawn past the right edge
+                        if self.x_pos + self.char_width() > self.width() {
                             self.newline();
                         }
-                        if self.y_pos + bitmap_char.height() > self.height() {
-                            self.clear();
-                        }
+                        self.remember_char(c);
                         self.write_rendered_char(bitmap_char);
                     },
                     None => {}
@@ -96,51 +949,155 @@ impl ScreenWriter {
         }
     }
 
+    /// Advances to the next tab stop by writing spaces, or to the start of the next row
+    /// if the stop is past the right edge.
+    fn tab(&mut self) {
+        let col = self.x_pos / self.char_width();
+        let next = (col / TAB_WIDTH + 1) * TAB_WIDTH;
+        if next * self.char_width() >= self.width() {
+            self.newline();
+            return;
+        }
+        for _ in col..next {
+            if let Some(space) = get_raster(' ', FontWeight::Regular, Size16) {
+                self.remember_char(' ');
+                self.write_rendered_char(space);
+            }
+        }
+    }
+
     fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
+        let width = rendered_char.width() * self.scale;
+        self.draw_rendered_char(self.x_pos, self.y_pos, rendered_char, self.color);
+        self.x_pos += width;
+    }
+
+    /// Draws a glyph with its top left corner at (x_pos, y_pos), each font pixel
+    /// becoming a `scale` x `scale` block.
+    fn draw_rendered_char(&mut self, x_pos: usize, y_pos: usize, rendered_char: RasterizedChar, color: Color) {
+        let scale = self.scale;
         for (y, row) in rendered_char.raster().iter().enumerate() {
             for (x, byte) in row.iter().enumerate() {
-                self.write_pixel(self.x_pos + x, self.y_pos + y, *byte);
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        self.write_pixel(x_pos + x * scale + dx, y_pos + y * scale + dy, *byte, color);
+                    }
+                }
             }
         }
-        self.x_pos += rendered_char.width();
     }
 
-    pub fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * usize::from(self.info.stride) + x;
-        let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [intensity / 4, intensity, intensity / 2, 0],
-            PixelFormat::Bgr => [intensity / 2, intensity, intensity / 4, 0],
-            other => {
-                // set a supported (but invalid) pixel format before panicking to avoid a double
-                // panic; it might not be readable though
-                self.info.pixel_format = PixelFormat::Rgb;
-                panic!("pixel format {:?} not supported in logger", other)
+    /// Draws a glyph pixel: `color` blended onto the background by the glyph's coverage
+    /// `intensity`.
+    pub fn write_pixel(&mut self, x: usize, y: usize, intensity: u8, color: Color) {
+        let (i, background) = (u16::from(intensity), self.background);
+        let blend = |fg: u8, bg: u8| ((u16::from(fg) * i + u16::from(bg) * (0xff - i)) / 0xff) as u8;
+        self.draw_pixel(x, y, blend(color.r, background.r), blend(color.g, background.g), blend(color.b, background.b));
+    }
+
+    /// Draws a line from (x0, y0) to (x1, y1) using Bresenham's algorithm. Points outside
+    /// the framebuffer are clipped.
+    pub fn draw_line(&mut self, x0: isize, y0: isize, x1: isize, y1: isize, color: Color) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+        loop {
+            if x >= 0 && y >= 0 {
+                self.draw_pixel(x as usize, y as usize, color.r, color.g, color.b);
             }
-        };
-        let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * usize::from(bytes_per_pixel);
-        self.framebuffer[byte_offset..(byte_offset + usize::from(bytes_per_pixel))]
-            .copy_from_slice(&color[..usize::from(bytes_per_pixel)]);
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
     }
 
-    pub fn draw_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8) {
-        let pixel_offset = y * usize::from(self.info.stride) + x;
-        let color = match self.info.pixel_format {
-            PixelFormat::Rgb => [r, g, b, 0],
-            PixelFormat::Bgr => [b, g, r, 0],
-            other => {
-                // set a supported (but invalid) pixel format before panicking to avoid a double
-                // panic; it might not be readable though
-                self.info.pixel_format = PixelFormat::Rgb;
-                panic!("pixel format {:?} not supported in logger", other)
+    /// Draws a `w` x `h` rectangle with its top left corner at (x, y), either filled or
+    /// as a one pixel outline. Parts outside the framebuffer are clipped.
+    pub fn draw_rect(&mut self, x: usize, y: usize, w: usize, h: usize, color: Color, filled: bool) {
+        if w == 0 || h == 0 {
+            return;
+        }
+        if filled {
+            for py in y..y.saturating_add(h).min(self.height()) {
+                for px in x..x.saturating_add(w).min(self.width()) {
+                    self.draw_pixel(px, py, color.r, color.g, color.b);
+                }
             }
-        };
+        } else {
+            let (left, top) = (x as isize, y as isize);
+            let (right, bottom) = (left + w as isize - 1, top + h as isize - 1);
+            self.draw_line(left, top, right, top, color);
+            self.draw_line(left, bottom, right, bottom, color);
+            self.draw_line(left, top, left, bottom, color);
+            self.draw_line(right, top, right, bottom, color);
+        }
+    }
+
+    /// Bytes of a pixel of `color` in the framebuffer's layout; only the first
+    /// `bytes_per_pixel` of them are used.
+    fn encode(&self, color: Color) -> [u8; 4] {
+        let Color { r, g, b } = color;
+        match self.layout {
+            PixelLayout::Rgb => [r, g, b, 0],
+            PixelLayout::Bgr => [b, g, r, 0],
+            PixelLayout::Gray => {
+                let luma = ((u16::from(r) * 77 + u16::from(g) * 150 + u16::from(b) * 29) >> 8) as u8;
+                [luma, 0, 0, 0]
+            }
+        }
+    }
+
+    /// Offset of pixel (x, y) in the framebuffer bytes. Rows are `stride` pixels apart,
+    /// which may be more than `width` when scanlines are padded.
+    fn byte_offset(&self, x: usize, y: usize) -> usize {
+        (y * self.info.stride + x) * self.info.bytes_per_pixel
+    }
+
+    /// Draws `color` at (x, y) and reads it back, then puts the old pixel back. Returns
+    /// whether the pixel held `color` in between; false outside the framebuffer.
+    pub fn check_pixel(&mut self, x: usize, y: usize, color: Color) -> bool {
+        if x >= self.width() || y >= self.height() {
+            return false;
+        }
+        let (offset, bytes_per_pixel) = (self.byte_offset(x, y), self.info.bytes_per_pixel);
+        let expected = self.encode(color);
+        let mut old = [0; 4];
+        old[..bytes_per_pixel].copy_from_slice(&self.pixels()[offset..offset + bytes_per_pixel]);
+        self.draw_pixel(x, y, color.r, color.g, color.b);
+        let held = self.pixels()[offset..offset + bytes_per_pixel] == expected[..bytes_per_pixel];
+        self.pixels()[offset..offset + bytes_per_pixel].copy_from_slice(&old[..bytes_per_pixel]);
+        self.present();
+        held
+    }
+
+    /// Sets a single pixel; coordinates outside the framebuffer are ignored.
+    pub fn draw_pixel(&mut self, x: usize, y: usize, r: u8, g: u8, b: u8) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let color = self.encode(Color::new(r, g, b));
         let bytes_per_pixel = self.info.bytes_per_pixel;
-        let byte_offset = pixel_offset * usize::from(bytes_per_pixel);
-        self.framebuffer[byte_offset..(byte_offset + usize::from(bytes_per_pixel))]
+        let byte_offset = self.byte_offset(x, y);
+        let pixels = self.pixels();
+        pixels[byte_offset..(byte_offset + usize::from(bytes_per_pixel))]
             .copy_from_slice(&color[..usize::from(bytes_per_pixel)]);
-        let _ = unsafe { ptr::read_volatile(&self.framebuffer[byte_offset]) };
+        let _ = unsafe { ptr::read_volatile(&pixels[byte_offset]) };
+        self.dirty = Some(match self.dirty {
+            Some((top, bottom)) => (top.min(y), bottom.max(y)),
+            None => (y, y),
+        });
     }
 
 }
@@ -153,6 +1110,118 @@ impl fmt::Write for ScreenWriter {
         for c in s.chars() {
             self.write_char(c);
         }
+        self.present();
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// A screen of `cols` x `rows` characters on a fresh framebuffer with `stride`
+    /// pixels per scanline, without the status bar
+    fn screen(cols: usize, rows: usize, stride: usize) -> ScreenWriter {
+        let (width, height) = (cols * CHAR_WIDTH, rows * LINE_HEIGHT);
+        let info = FrameBufferInfo {
+            byte_len: stride * height * 4,
+            width,
+            height,
+            pixel_format: PixelFormat::Bgr,
+            bytes_per_pixel: 4,
+            stride,
+        };
+        let mut screen = ScreenWriter::new(vec![0; info.byte_len].leak(), info);
+        screen.set_status_bar(false);
+        screen
+    }
+
+    /// Whether pixel row `y` is all background from `x` on
+    fn blank_from(screen: &ScreenWriter, x: usize, y: usize) -> bool {
+        let start = screen.byte_offset(x, y);
+        screen.framebuffer[start..screen.byte_offset(screen.width(), y)].iter().all(|&byte| byte == 0)
+    }
+
+    #[test]
+    fn backspaces_leave_nothing_behind() {
+        let mut screen = screen(40, 5, 40 * CHAR_WIDTH);
+        let mut line = String::from("hello");
+        write!(screen, "> {}", line).unwrap();
+        for _ in 0..3 {
+            // what the shell's redraw_line does after taking a character off
+            line.pop();
+            write!(screen, "\r> {}", line).unwrap();
+            screen.clear_row_from(2 + line.len());
+            screen.move_to_column(2 + line.len());
+        }
+        assert_eq!(screen.row_text(0).unwrap(), "> he");
+        for y in 0..LINE_HEIGHT {
+            assert!(blank_from(&screen, 4 * CHAR_WIDTH, y));
+        }
+    }
+
+    #[test]
+    fn write_at_puts_the_position_back() {
+        let mut screen = screen(40, 5, 40 * CHAR_WIDTH);
+        write!(screen, "> abcd\n> ").unwrap();
+        screen.write_at(3, 0, format_args!("XY"));
+        write!(screen, "ef").unwrap();
+        assert_eq!(screen.row_text(1).unwrap(), "> aXYd");
+        assert_eq!(screen.row_text(0).unwrap(), "> ef");
+    }
+
+    #[test]
+    fn padded_scanlines_are_skipped() {
+        // 10 pixels of padding after each of the 8 * CHAR_WIDTH visible ones
+        let (width, stride) = (8 * CHAR_WIDTH, 8 * CHAR_WIDTH + 10);
+        let mut screen = screen(8, 3, stride);
+        assert_eq!(screen.byte_offset(0, 1), stride * 4);
+        assert_eq!(screen.byte_offset(3, 2), (2 * stride + 3) * 4);
+        screen.draw_pixel(width - 1, 1, 0x11, 0x22, 0x33);
+        screen.present();
+        let offset = screen.byte_offset(width - 1, 1);
+        assert_eq!(screen.framebuffer[offset..offset + 3], [0x33, 0x22, 0x11]);
+        // nothing lands in the padding, nor wraps into the next scanline
+        let padding = screen.byte_offset(width, 1)..screen.byte_offset(0, 2);
+        assert!(screen.framebuffer[padding].iter().all(|&byte| byte == 0));
+        assert!(blank_from(&screen, 0, 2));
+    }
+
+    #[test]
+    fn long_lines_wrap_onto_the_next_rows() {
+        let mut screen = screen(10, 5, 10 * CHAR_WIDTH);
+        write!(screen, "{}", "0123456789".repeat(2) + "abcde").unwrap();
+        assert_eq!(screen.row_text(2).unwrap(), "0123456789");
+        assert_eq!(screen.row_text(1).unwrap(), "0123456789");
+        assert_eq!(screen.row_text(0).unwrap(), "abcde");
+        assert_eq!(screen.row_text(3), None);
+        assert_eq!(screen.y_pos, 2 * LINE_HEIGHT);
+    }
+
+    #[test]
+    fn carriage_return_overwrites_the_row() {
+        let mut screen = screen(10, 3, 10 * CHAR_WIDTH);
+        write!(screen, "abc\rX").unwrap();
+        assert_eq!(screen.row_text(0).unwrap(), "Xbc");
+        assert_eq!(screen.x_pos, CHAR_WIDTH);
+    }
+
+    #[test]
+    fn a_full_screen_region_scrolls_like_none() {
+        let mut plain = screen(10, 5, 10 * CHAR_WIDTH);
+        let mut region = screen(10, 5, 10 * CHAR_WIDTH);
+        region.scroll_region(1, 2);
+        for i in 0..8 {
+            writeln!(plain, "line {}", i).unwrap();
+            writeln!(region, "line {}", i).unwrap();
+        }
+        region.scroll_region(0, usize::MAX);
+        assert_eq!(region.region, None);
+        assert_eq!(region.framebuffer, plain.framebuffer);
+        assert_eq!((region.x_pos, region.y_pos), (plain.x_pos, plain.y_pos));
+        writeln!(plain, "line 8").unwrap();
+        writeln!(region, "line 8").unwrap();
+        assert_eq!(region.framebuffer, plain.framebuffer);
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use kernel::{interrupts, time};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator};
+use crate::screen::{Color, Writer, screenwriter};
+use crate::{allocator, frame_allocator, user};
+
+/// Bytes `heap` allocates; more than the largest slab class, so freeing it gives the
+/// memory back to the heap instead of keeping a slab
+const HEAP_CHECK_BYTES: usize = 16 * 1024;
+
+/// TSC cycles `timer` waits for a tick before giving up; one to a few seconds
+const TIMER_CHECK_CYCLES: u64 = 3_000_000_000;
+
+/// What became of one check
+pub enum Outcome {
+    Pass,
+    /// The check doesn't apply here, e.g. no framebuffer to draw on
+    Skip(&'static str),
+    Fail(String),
+}
+
+type Check = fn() -> Outcome;
+
+/// The checks `run` goes through, by name
+pub const CHECKS: &[(&str, Check)] = &[
+    ("heap", heap),
+    ("growth", growth),
+    ("framebuffer", framebuffer),
+    ("timer", timer),
+    ("frames", frames),
+    ("syscall", syscall),
+];
+
+/// Runs every check, printing PASS, FAIL or SKIP for each and then a summary. Returns
+/// whether none failed.
+pub fn run(out: &mut dyn Write) -> bool {
+    let mut failed = 0;
+    for (name, check) in CHECKS {
+        match check() {
+            Outcome::Pass => writeln!(out, "\x1b[32mPASS\x1b[0m {}", name),
+            Outcome::Skip(why) => writeln!(out, "\x1b[33mSKIP\x1b[0m {}: {}", name, why),
+            Outcome::Fail(why) => {
+                failed += 1;
+                writeln!(out, "\x1b[31mFAIL\x1b[0m {}: {}", name, why)
+            }
+        }.ok();
+    }
+    writeln!(out, "{} of {} checks failed", failed, CHECKS.len()).ok();
+    failed == 0
+}
+
+/// Allocating and freeing memory leaves the heap as it was.
+pub fn heap() -> Outcome {
+    let before = allocator::snapshot();
+    let mut block: Vec<u8> = Vec::with_capacity(HEAP_CHECK_BYTES);
+    block.resize(HEAP_CHECK_BYTES, 0x5a);
+    let during = allocator::snapshot();
+    let intact = block.iter().all(|&byte| byte == 0x5a);
+    drop(block);
+    let after = allocator::snapshot();
+    if during.used < before.used + HEAP_CHECK_BYTES {
+        Outcome::Fail(format!("{} byte allocation only took {} bytes", HEAP_CHECK_BYTES, during.used.saturating_sub(before.used)))
+    } else if !intact {
+        Outcome::Fail(String::from("allocated memory didn't keep what was written"))
+    } else if after.used != before.used {
+        Outcome::Fail(format!("{} bytes used before, {} after freeing", before.used, after.used))
+    } else {
+        Outcome::Pass
+    }
+}
+
+/// An allocation bigger than any free block grows the heap instead of failing. Unlike
+/// the host unit test, with real frames mapped through the kernel's mapper.
+pub fn growth() -> Outcome {
+    let before = allocator::snapshot();
+    let bytes = allocator::heap_dump().largest_free + HEAP_CHECK_BYTES;
+    if bytes > allocator::GROWTH_MAX {
+        return Outcome::Skip("the heap is too big to outgrow");
+    }
+    let mut block: Vec<u8> = Vec::new();
+    if block.try_reserve_exact(bytes).is_err() {
+        return Outcome::Fail(format!("{} byte allocation failed instead of growing the heap", bytes));
+    }
+    let during = allocator::snapshot();
+    if during.total <= before.total {
+        Outcome::Fail(format!("{} byte allocation didn't grow the {} byte heap", bytes, before.total))
+    } else {
+        Outcome::Pass
+    }
+}
+
+/// A pixel written to the framebuffer reads back the same.
+pub fn framebuffer() -> Outcome {
+    let Some(mut screen) = screenwriter() else { return Outcome::Skip("no framebuffer") };
+    let colors = [Color::new(0xff, 0, 0), Color::new(0, 0xff, 0), Color::new(0, 0, 0xff)];
+    match colors.iter().find(|&&color| !screen.check_pixel(0, 0, color)) {
+        Some(color) => Outcome::Fail(format!("pixel (0, 0) didn't read back as {:?}", color)),
+        None => Outcome::Pass,
+    }
+}
+
+/// The timer tick count advances.
+pub fn timer() -> Outcome {
+    let (start, cycles) = (interrupts::current_ticks(), time::rdtsc());
+    while interrupts::current_ticks() == start {
+        if time::rdtsc().wrapping_sub(cycles) > TIMER_CHECK_CYCLES {
+            return Outcome::Fail(format!("no tick after {} cycles, still at {}", TIMER_CHECK_CYCLES, start));
+        }
+        core::hint::spin_loop();
+    }
+    Outcome::Pass
+}
+
+/// A physical frame can be allocated and freed, and the count of used frames follows.
+pub fn frames() -> Outcome {
+    frame_allocator::with_frames(|frames| {
+        let before = frames.frames_used();
+        let Some(frame) = frames.allocate_frame() else { return Outcome::Fail(String::from("no free frame")) };
+        let during = frames.frames_used();
+        unsafe { frames.deallocate_frame(frame) };
+        let after = frames.frames_used();
+        if during != before + 1 {
+            Outcome::Fail(format!("{} frames used before allocating, {} after", before, during))
+        } else if after != before {
+            Outcome::Fail(format!("{} frames used before, {} after freeing", before, after))
+        } else {
+            Outcome::Pass
+        }
+    })
+}
+
+/// A ring 3 program's `write` shows up on screen, and its `exit` code comes back.
+pub fn syscall() -> Outcome {
+    // the message should get a row of its own
+    if screenwriter().is_some_and(|screen| screen.row_text(0).is_some_and(|row| !row.is_empty())) {
+        writeln!(Writer).ok();
+    }
+    let code = match user::syscall_test() {
+        Ok(code) => code,
+        Err(err) => return Outcome::Fail(String::from(err)),
+    };
+    let expected = core::str::from_utf8(user::SYSCALL_MESSAGE).unwrap().trim_end();
+    let shown = screenwriter().map(|screen| screen.row_text(1).unwrap_or_default());
+    if code != user::SYSCALL_EXIT_CODE {
+        Outcome::Fail(format!("exited with {}, not {}", code, user::SYSCALL_EXIT_CODE))
+    } else if shown.as_ref().is_some_and(|row| row != expected) {
+        Outcome::Fail(format!("screen shows {:?} instead of {:?}", shown.unwrap(), expected))
+    } else {
+        Outcome::Pass
+    }
+}
@@ -0,0 +1,1416 @@
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::slice;
+use bootloader_api::info::MemoryRegionKind;
+use kernel::{cpuid, interrupts, keyboard, log, power, rtc, speaker, time};
+use kernel::sync::IrqMutex;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::PhysAddr;
+use x86_64::instructions::port::Port;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame};
+use crate::screen::{self, ScreenGuard, Writer, screenwriter};
+use crate::{allocator, args, calc, conv, demand, frame_allocator, fs, memory, selftest, table, task, user};
+
+/// Most commands kept for recall with the arrow keys
+const HISTORY_LEN: usize = 32;
+
+/// Prompt when `prompt` hasn't set another
+const DEFAULT_PROMPT: &str = "> ";
+
+/// Most variables `set` and `read` may store at once
+const MAX_VARS: usize = 32;
+
+/// Printed above the message of the day, if the screen is wide enough for it
+const BANNER_ART: [&str; 5] = [
+    r" _                              _",
+    r"| | __  ___  _ __  _ __    ___ | |",
+    r"| |/ / / _ \| '__|| '_ \  / _ \| |",
+    r"|   < |  __/| |   | | | ||  __/| |",
+    r"|_|\_\ \___||_|   |_| |_| \___||_|",
+];
+
+/// A built-in command as listed by `help`
+struct Command {
+    /// Name followed by the arguments it takes
+    usage: &'static str,
+    handler: Handler,
+    /// One line for the `help` list
+    summary: &'static str,
+    /// Longer text shown by `help <command>`
+    details: &'static str,
+}
+
+/// Runs a command with its arguments and the piped input, if any, writing to the output
+type Handler = fn(&mut Shell, &[&str], Option<&str>, &mut dyn Write);
+
+impl Command {
+    fn name(&self) -> &'static str {
+        self.usage.split_whitespace().next().unwrap_or(self.usage)
+    }
+}
+
+/// Built-in commands, listed by `help`, described by `help <command>` and used for Tab
+/// completion.
+const COMMANDS: &[Command] = &[
+    Command { usage: "echo [text...]", handler: cmd_echo, summary: "print text",
+        details: "Prints its arguments separated by spaces. With no arguments it passes piped input through." },
+    Command { usage: "clear [color]", handler: cmd_clear, summary: "clear screen, to a color name or #RRGGBB",
+        details: "Clears the screen. Given a basic color name (black, red, green, yellow, blue, magenta, cyan,\nwhite) or #RRGGBB, that becomes the background color." },
+    Command { usage: "ticks", handler: cmd_ticks, summary: "show timer ticks",
+        details: "Prints the number of timer interrupts since startup." },
+    Command { usage: "date", handler: cmd_date, summary: "show the date and time",
+        details: "Prints the date and time read from the CMOS real time clock." },
+    Command { usage: "uptime", handler: cmd_uptime, summary: "show time since startup as h:mm:ss",
+        details: "Prints the time since startup, counted in timer ticks." },
+    Command { usage: "sleep <ms>", handler: cmd_sleep, summary: "wait for a while",
+        details: "Waits for <ms> milliseconds, rounded to timer ticks. Keys typed meanwhile are kept for later." },
+    Command { usage: "memstat", handler: cmd_memstat, summary: "show allocator usage",
+        details: "Prints the bytes used and available on the heap and the reserve pool, and the most used at once." },
+    Command { usage: "stack", handler: cmd_stack, summary: "show kernel stack usage",
+        details: "Compares the stack pointer with the bounds of the stack the shell runs on, captured at boot,\nand prints the bytes used and left." },
+    Command { usage: "meminfo", handler: cmd_meminfo, summary: "show where physical memory went",
+        details: "Splits physical memory into reserved, bootloader, kernel image and usable memory, and shows\nhow much of the usable memory and the heap is in use." },
+    Command { usage: "allocstat", handler: cmd_allocstat, summary: "show allocator counters",
+        details: "Prints the live and peak bytes, and the number of allocations, frees and failed allocations." },
+    Command { usage: "heapdump", handler: cmd_heapdump, summary: "dump the heap free list to serial",
+        details: "Writes every free heap block to serial and prints a summary with the fragmentation." },
+    Command { usage: "setfont big|small", handler: cmd_setfont, summary: "switch font size",
+        details: "Draws text at double (big) or normal (small) size." },
+    Command { usage: "resolution", handler: cmd_resolution, summary: "show screen size",
+        details: "Prints the framebuffer size in pixels and in characters." },
+    Command { usage: "beep [hz] [ms]", handler: cmd_beep, summary: "sound the PC speaker",
+        details: "Sounds the PC speaker at [hz] (880 by default) for [ms] milliseconds (200 by default, at\nmost 5000)." },
+    Command { usage: "bench alloc|scroll", handler: cmd_bench, summary: "time allocations or scrolling",
+        details: "alloc runs a fixed mix of allocations and frees and prints how long it took, in ticks,\nnanoseconds and TSC cycles. scroll prints lines to scroll the screen, once copying the rows up\nand once repainting them from the scrollback, and compares the time per line." },
+    Command { usage: "statusbar on|off", handler: cmd_statusbar, summary: "show or hide the status bar",
+        details: "Shows or hides the bottom row with the uptime, memory use and number of tasks." },
+    Command { usage: "cursor on|off", handler: cmd_cursor, summary: "show or hide the blinking cursor",
+        details: "Shows or hides the block that blinks at the write position." },
+    Command { usage: "at <col> <row> <text...>", handler: cmd_at, summary: "write text anywhere on the screen",
+        details: "Writes <text> starting at character cell <col>, <row>, counted from 0 at the top left, and\nputs the write position back. Text past the end of a row carries on at the start of the next." },
+    Command { usage: "region <top> <bottom>|off", handler: cmd_region, summary: "scroll only some rows of the screen",
+        details: "Text rows <top> to <bottom>, counted from 0, become the only ones that scroll; the rows\noutside keep what they show, e.g. for `at` to write to. `region off`, or a region covering\nevery row, scrolls the whole screen again." },
+    Command { usage: "framestat", handler: cmd_framestat, summary: "show physical frame usage",
+        details: "Prints how many 4 KiB physical frames are allocated out of the usable ones." },
+    Command { usage: "pmm alloc|free <addr>", handler: cmd_pmm, summary: "allocate or free a physical frame",
+        details: "pmm alloc takes a 4 KiB frame from the frame allocator and prints its physical address.\npmm free <hexaddr> gives back a frame pmm alloc handed out; anything else is refused." },
+    Command { usage: "demand <addr>", handler: cmd_demand, summary: "reserve a page and fault it in",
+        details: "Reserves the page at the hex address <addr>, in 0x600000000000 to 0x600040000000, and reads\nit. The read faults and the page fault handler maps a zeroed frame; reading again doesn't fault." },
+    Command { usage: "lsmem", handler: cmd_lsmem, summary: "list the boot memory map",
+        details: "Lists the memory regions the bootloader reported, with their kind." },
+    Command { usage: "hexdump [text...]", handler: cmd_hexdump, summary: "hex dump text, piped input, or with --addr <addr> <len> memory",
+        details: "Hex dumps its arguments, or piped input when there are none. With --addr <hexaddr> <len> it\ndumps <len> bytes of physical memory instead." },
+    Command { usage: "peek <addr> [len]", handler: cmd_peek, summary: "hex dump physical memory",
+        details: "Hex dumps [len] bytes (16 by default) of physical memory starting at the hex address <addr>." },
+    Command { usage: "poke <addr> <byte>", handler: cmd_poke, summary: "write a byte of physical memory",
+        details: "Writes the hex <byte> to the physical hex address <addr>. Only addresses in the memory map\nare accepted." },
+    Command { usage: "port read|write <port> [byte]", handler: cmd_port, summary: "read or write an I/O port",
+        details: "Reads a byte from the hex I/O <port> (0 to ffff), or writes the hex <byte> to it. Writing to\nthe wrong port can hang or reset the machine, so `write` only goes ahead with --danger.\nReading can have side effects too: reading port 60 takes the byte from the keyboard." },
+    Command { usage: "memtest <addr> <len>", handler: cmd_memtest, summary: "pattern test a range of physical memory",
+        details: "Writes a walking-ones pattern through <len> bytes of physical memory from the hex address\n<addr>, reads it back and lists the bytes that differ. The frames must be usable and free;\nthey are taken from the frame allocator for the test, so nothing else lives there meanwhile." },
+    Command { usage: "dmesg", handler: cmd_dmesg, summary: "show the kernel log",
+        details: "Prints the lines logged to serial since startup, as many of the latest as the 16 KiB log\nbuffer holds." },
+    Command { usage: "loglevel [level]", handler: cmd_loglevel, summary: "show or set the serial log level",
+        details: "Shows the least important level still logged over serial, or sets it to debug, info, warn\nor error." },
+    Command { usage: "keymap us|de", handler: cmd_keymap, summary: "switch keyboard layout",
+        details: "Switches between the US and the German keyboard layout." },
+    Command { usage: "mirror on|off", handler: cmd_mirror, summary: "copy screen output to serial",
+        details: "Also writes everything printed on the screen to serial." },
+    Command { usage: "usertest", handler: cmd_usertest, summary: "run tiny programs in ring 3",
+        details: "Runs a program in ring 3 that returns a value, then one that prints through the write\nsystem call and exits with a code." },
+    Command { usage: "conv <value> <from> <to>", handler: cmd_conv, summary: "convert a number between bases 2, 8, 10 and 16",
+        details: "Converts <value> from base <from> to base <to>. The value may have a 0b, 0o or 0x prefix\nand _ separators." },
+    Command { usage: "selftest", handler: cmd_selftest, summary: "check the heap, screen, timer, frames and system calls",
+        details: "Allocates and frees heap memory and a physical frame, makes the heap grow, writes and\nreads back a pixel, waits for a timer tick and runs a ring 3 program that prints a line and\nexits, checking the line on screen. Prints PASS, FAIL or SKIP for each." },
+    Command { usage: "cpuinfo", handler: cmd_cpuinfo, summary: "show the CPU model and features",
+        details: "Prints the vendor, family, model and stepping, the brand string if the CPU has one, and whether\nit has an APIC, x2APIC, SSE and an invariant TSC, all from CPUID." },
+    Command { usage: "apic", handler: cmd_apic, summary: "show local APIC registers",
+        details: "Prints the local APIC ID, version, task priority, spurious vector, the LVT entries and the\ntimer counts in hex, read through the APIC's mapping. Bit 16 of an LVT entry masks it.\nAlso counts spurious interrupts, from the 8259 PIC too." },
+    Command { usage: "msgbox <text>", handler: cmd_msgbox, summary: "show text in a box until a key is pressed",
+        details: "Draws <text> in a bordered box in the middle of the screen, and puts back what was under it\nonce a key is pressed." },
+    Command { usage: "calc <expression>", handler: cmd_calc, summary: "evaluate an integer expression",
+        details: "Evaluates + - * / % with parentheses and unary minus over 64-bit signed integers, e.g.\ncalc 2 + 3 * (4 - 1). Division rounds towards zero." },
+    Command { usage: "progress", handler: cmd_progress, summary: "draw a progress bar filling up",
+        details: "Fills a progress bar over about two seconds, redrawing it in place. Ctrl+C stops it early." },
+    Command { usage: "brk", handler: cmd_brk, summary: "hit a breakpoint (int3) and carry on",
+        details: "Executes int3. The breakpoint handler logs the address to serial and execution resumes." },
+    Command { usage: "spawn", handler: cmd_spawn, summary: "start two tasks that count alongside the shell",
+        details: "Starts two kernel tasks that print a few numbers each, taking turns with the shell." },
+    Command { usage: "irqdemo", handler: cmd_irqdemo, summary: "hold a lock the timer takes, without hanging",
+        details: "Holds the IrqMutex around a counter the timer interrupt adds to on every tick, for a fifth of\na second. A spin::Mutex there would hang on the first tick, the handler spinning on a lock the\ncode it interrupted holds; the IrqMutex keeps interrupts off instead, so the counter doesn't\nmove until it's released." },
+    Command { usage: "reboot", handler: cmd_reboot, summary: "restart the machine",
+        details: "Resets through the reset control register, then the keyboard controller; halts if neither works." },
+    Command { usage: "shutdown", handler: cmd_shutdown, summary: "power off (QEMU and Bochs only)",
+        details: "Powers off through the ports QEMU and Bochs provide for it; halts on other machines." },
+    Command { usage: "ls", handler: cmd_ls, summary: "list files",
+        details: "Lists the files in the RAM filesystem with their sizes, and the space they take up in all.\nFiles live on the heap, so they are gone after a reboot." },
+    Command { usage: "cat <name>", handler: cmd_cat, summary: "print a file",
+        details: "Prints the file <name>, or a hex dump of it if it isn't text." },
+    Command { usage: "write <name> [text...]", handler: cmd_write, summary: "write text to a file",
+        details: "Creates the file <name> holding the text, followed by a newline, or with no text the piped\ninput. An existing file is replaced." },
+    Command { usage: "rm <name>", handler: cmd_rm, summary: "delete a file",
+        details: "Deletes the file <name>." },
+    Command { usage: "prompt [template]", handler: cmd_prompt, summary: "show or set the prompt",
+        details: "Sets the prompt to <template>, in which \\t stands for the uptime, \\m for the heap in use\nand \\\\ for a backslash, worked out each time the prompt is drawn. The backslashes have to be\ndoubled on the command line, as in prompt \"\\\\t \\\\m> \". An empty template goes back to \"> \";\nwith none, the current one is shown. Control characters aren't allowed in it." },
+    Command { usage: "set [name=value]", handler: cmd_set, summary: "set a variable, or list them",
+        details: "Sets the variable <name> to <value>, which $name then expands to in any command line.\nWithout an argument it lists the variables. At most 32 can be set." },
+    Command { usage: "read [name]", handler: cmd_read, summary: "read a line from the keyboard",
+        details: "Waits for a line typed on the keyboard and stores it under [name], or prints it when no name\nis given." },
+    Command { usage: "motd", handler: cmd_motd, summary: "show the startup banner again",
+        details: "Prints the banner with the kernel name, version and build date, as at startup." },
+    Command { usage: "history", handler: cmd_history, summary: "list previous commands; !N runs number N again",
+        details: "Lists the commands entered so far with their numbers. A line !N runs command number N again." },
+    Command { usage: "help [command]", handler: cmd_help, summary: "this message, or more about a command",
+        details: "Lists the built-ins, or with a command name describes that command." },
+];
+
+/// Commands that work but aren't listed by `help` or completed
+const HIDDEN_COMMANDS: &[Command] = &[
+    // shows what a panic looks like
+    Command { usage: "panic", handler: cmd_panic, summary: "", details: "" },
+    #[cfg(debug_assertions)]
+    Command { usage: "overflow", handler: cmd_overflow, summary: "", details: "" },
+];
+
+pub struct Shell {
+    buf: String,
+    /// Byte index in `buf` where typed characters are inserted
+    cursor: usize,
+    /// Previous commands, oldest first
+    history: Vec<String>,
+    /// Entry of `history` shown in the line while browsing with the arrow keys
+    history_pos: Option<usize>,
+    /// Number of the oldest entry in `history`. Commands are numbered from 1 as they are
+    /// entered, and keep their number when older ones are dropped.
+    history_first: usize,
+    /// Frames handed out by `pmm alloc`, which `pmm free` may give back
+    pmm_frames: Vec<PhysFrame>,
+    /// Variables set by `set` and `read`, expanded as `$NAME`; at most `MAX_VARS`
+    vars: BTreeMap<String, String>,
+    /// Shown under the banner at startup and by `motd`
+    motd: String,
+    /// Set by `prompt`; `render_prompt` expands it each time the prompt is drawn
+    prompt: String,
+    /// Characters of the prompt as last drawn, where the line being typed starts
+    prompt_len: usize,
+}
+
+impl Shell {
+    fn new() -> Self { Self { buf: String::new(), cursor: 0, history: Vec::new(), history_pos: None, history_first: 1, pmm_frames: Vec::new(), vars: BTreeMap::new(), motd: motd(), prompt: String::from(DEFAULT_PROMPT), prompt_len: 0 } }
+    fn remember(&mut self) {
+        self.history_pos = None;
+        if self.buf.trim().is_empty() || self.history.last() == Some(&self.buf) {
+            return;
+        }
+        if self.history.len() == HISTORY_LEN {
+            self.history.remove(0);
+            self.history_first += 1;
+        }
+        self.history.push(self.buf.clone());
+    }
+    /// Replaces a `!N` line with command number N from the history, echoing it. Returns
+    /// false, with a message, if there's no such command.
+    fn expand_history(&mut self) -> bool {
+        let Some(number) = self.buf.trim().strip_prefix('!') else { return true };
+        let entry = number.parse::<usize>().ok()
+            .and_then(|n| n.checked_sub(self.history_first))
+            .and_then(|i| self.history.get(i));
+        match entry {
+            Some(command) => {
+                self.buf = command.clone();
+                writeln!(Writer, "{}", self.buf).ok();
+                true
+            }
+            None => {
+                let last = self.history_first + self.history.len();
+                if self.history.is_empty() {
+                    writeln!(Writer, "!{}: the history is empty", number).ok();
+                } else {
+                    writeln!(Writer, "!{}: not in the history, try {} to {}", number, self.history_first, last - 1).ok();
+                }
+                false
+            }
+        }
+    }
+    /// Shows the previous (`back`) or next history entry in the line; going forward
+    /// past the newest entry leaves an empty line.
+    fn recall(&mut self, back: bool) {
+        let pos = match (self.history_pos, back) {
+            (None, true) if !self.history.is_empty() => Some(self.history.len() - 1),
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.history.len() => Some(i + 1),
+            (Some(_), false) => None,
+            (None, _) => return,
+        };
+        self.history_pos = pos;
+        self.buf = pos.map(|i| self.history[i].clone()).unwrap_or_default();
+        self.cursor = self.buf.len();
+        self.redraw_line();
+    }
+    /// Sets variable `name`, unless it isn't a valid name or there are already
+    /// `MAX_VARS` others.
+    fn set_var(&mut self, name: &str, value: &str) -> Result<(), &'static str> {
+        if name.is_empty() || !name.chars().all(args::is_name_char) {
+            return Err("names may only have letters, digits and _");
+        }
+        if self.vars.len() == MAX_VARS && !self.vars.contains_key(name) {
+            return Err("too many variables");
+        }
+        self.vars.insert(String::from(name), String::from(value));
+        Ok(())
+    }
+    /// Prints the banner and the message of the day. Lines are cut to the screen width,
+    /// and the art is left out altogether when it doesn't fit, rather than wrapping.
+    pub fn print_motd(&self, out: &mut dyn Write) {
+        let cols = screenwriter().map_or(usize::MAX, |screen| screen.dimensions().2);
+        if BANNER_ART.iter().all(|line| line.len() <= cols) {
+            for line in BANNER_ART {
+                writeln!(out, "{line}").ok();
+            }
+        }
+        for line in self.motd.lines() {
+            let end = line.char_indices().nth(cols).map_or(line.len(), |(i, _)| i);
+            writeln!(out, "{}", &line[..end]).ok();
+        }
+    }
+    pub fn prompt(&mut self) {
+        let prompt = render_prompt(&self.prompt);
+        self.prompt_len = prompt.chars().count();
+        write!(Writer, "{}", prompt).ok();
+    }
+    fn redraw_line(&mut self) {
+        // redraw prompt + buffer, then blank whatever is left of the old line
+        write!(Writer, "\r").ok();
+        self.prompt();
+        write!(Writer, "{}", self.buf).ok();
+        screen::clear_row_from(self.prompt_len + self.buf.chars().count());
+        screen::move_to_column(self.prompt_len + self.buf[..self.cursor].chars().count());
+    }
+    /// Byte index of the start of the word before the cursor (or the one it's in),
+    /// skipping whitespace first
+    fn prev_word(&self) -> usize {
+        let before = &self.buf[..self.cursor];
+        let end = before.trim_end().len();
+        before[..end].rfind(char::is_whitespace).map_or(0, |i| i + before[i..].chars().next().unwrap().len_utf8())
+    }
+    /// Byte index of the end of the word after the cursor (or the one it's in),
+    /// skipping whitespace first
+    fn next_word(&self) -> usize {
+        let after = &self.buf[self.cursor..];
+        let start = after.len() - after.trim_start().len();
+        self.cursor + after[start..].find(char::is_whitespace).map_or(after.len(), |i| start + i)
+    }
+    /// Byte index of the character boundary before the cursor
+    fn prev_boundary(&self) -> usize {
+        self.buf[..self.cursor].char_indices().next_back().map_or(0, |(i, _)| i)
+    }
+    /// Byte index of the character boundary after the cursor
+    fn next_boundary(&self) -> usize {
+        self.buf[self.cursor..].chars().next().map_or(self.cursor, |c| self.cursor + c.len_utf8())
+    }
+    /// Completes the command name being typed. A unique match is filled in; with several,
+    /// their common prefix is, or if that adds nothing they are listed.
+    fn complete(&mut self) {
+        if self.cursor != self.buf.len() || self.buf.contains(char::is_whitespace) {
+            return;
+        }
+        let matches: Vec<&str> = COMMANDS.iter()
+            .map(Command::name)
+            .filter(|name| name.starts_with(self.buf.as_str()))
+            .collect();
+        let Some(first) = matches.first() else { return };
+        if matches.len() == 1 {
+            self.buf = String::from(*first) + " ";
+        } else {
+            let common = matches.iter().fold(first.len(), |len, name| {
+                first.bytes().zip(name.bytes()).take(len).take_while(|(a, b)| a == b).count()
+            });
+            if common > self.buf.len() {
+                self.buf = String::from(&first[..common]);
+            } else {
+                writeln!(Writer).ok();
+                writeln!(Writer, "{}", matches.join("  ")).ok();
+            }
+        }
+        self.cursor = self.buf.len();
+        self.redraw_line();
+    }
+    fn move_cursor(&mut self, cursor: usize) {
+        if cursor != self.cursor {
+            self.cursor = cursor;
+            screen::move_to_column(self.prompt_len + self.buf[..cursor].chars().count());
+        }
+    }
+    pub fn handle_key(&mut self, key: DecodedKey) {
+        match key {
+            DecodedKey::Unicode('\n') => {
+                // show the whole line before moving past it
+                self.move_cursor(self.buf.len());
+                writeln!(Writer).ok();
+                if self.expand_history() {
+                    self.remember();
+                    self.execute();
+                }
+                self.buf.clear();
+                self.cursor = 0;
+                self.prompt();
+            }
+            DecodedKey::Unicode('\u{8}') | DecodedKey::RawKey(KeyCode::Backspace) => {
+                if self.cursor > 0 {
+                    self.cursor = self.prev_boundary();
+                    self.buf.remove(self.cursor);
+                    if self.cursor == self.buf.len() {
+                        // nothing after it to move left, so rubbing out the last cell will do
+                        write!(Writer, "\x08 \x08").ok();
+                    } else {
+                        self.redraw_line();
+                    }
+                }
+            }
+            DecodedKey::Unicode('\t') => self.complete(),
+            DecodedKey::Unicode('\u{7f}') | DecodedKey::RawKey(KeyCode::Delete) => {
+                if self.cursor < self.buf.len() {
+                    self.buf.remove(self.cursor);
+                    self.redraw_line();
+                }
+            }
+            DecodedKey::Unicode('\u{3}') => {
+                // Ctrl+C: drop the line and start over
+                self.move_cursor(self.buf.len());
+                writeln!(Writer, "^C").ok();
+                self.buf.clear();
+                self.cursor = 0;
+                self.history_pos = None;
+                self.prompt();
+            }
+            DecodedKey::Unicode('\u{c}') => {
+                // Ctrl+L: clear the screen, keeping the line being typed
+                screen::clear();
+                self.redraw_line();
+            }
+            DecodedKey::Unicode('\u{17}') => {
+                // Ctrl+W: erase the word before the cursor
+                let start = self.prev_word();
+                if start < self.cursor {
+                    self.buf.replace_range(start..self.cursor, "");
+                    self.cursor = start;
+                    self.redraw_line();
+                }
+            }
+            DecodedKey::Unicode('\u{15}') => {
+                // Ctrl+U: erase the line
+                self.buf.clear();
+                self.cursor = 0;
+                self.redraw_line();
+            }
+            DecodedKey::Unicode(c) if c.is_control() => {}
+            DecodedKey::Unicode(c) => {
+                self.buf.insert(self.cursor, c);
+                self.cursor += c.len_utf8();
+                if self.cursor == self.buf.len() {
+                    write!(Writer, "{}", c).ok();
+                } else {
+                    self.redraw_line();
+                }
+            }
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.recall(true),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.recall(false),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) if keyboard::modifiers().is_ctrl() => self.move_cursor(self.prev_word()),
+            DecodedKey::RawKey(KeyCode::ArrowRight) if keyboard::modifiers().is_ctrl() => self.move_cursor(self.next_word()),
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.move_cursor(self.prev_boundary()),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => self.move_cursor(self.next_boundary()),
+            DecodedKey::RawKey(KeyCode::Home) => self.move_cursor(0),
+            DecodedKey::RawKey(KeyCode::End) => self.move_cursor(self.buf.len()),
+            _ => {}
+        }
+    }
+    fn execute(&mut self) {
+        let input = core::mem::take(&mut self.buf);
+        let (stages, redirect) = match args::pipeline(&input, &self.vars) {
+            Ok(pipeline) => (pipeline.stages, pipeline.redirect),
+            Err(err) => {
+                writeln!(Writer, "error: {}", err).ok();
+                return;
+            }
+        };
+        if stages.len() == 1 && stages[0].is_empty() && redirect.is_none() {
+            return;
+        }
+        if stages.iter().any(|stage| stage.is_empty()) {
+            writeln!(Writer, "error: empty command in pipeline").ok();
+            return;
+        }
+        // every stage but the last writes into a string that becomes the next one's input,
+        // and so does the last one if its output goes to a file
+        let mut piped: Option<String> = None;
+        for (i, stage) in stages.iter().enumerate() {
+            let cmd = stage[0].as_str();
+            let args: Vec<&str> = stage[1..].iter().map(String::as_str).collect();
+            let stdin = piped.take();
+            if i + 1 < stages.len() {
+                let mut out = String::new();
+                self.run(cmd, &args, stdin.as_deref(), &mut out);
+                piped = Some(out);
+            } else if let Some(redirect) = &redirect {
+                let mut out = String::new();
+                self.run(cmd, &args, stdin.as_deref(), &mut out);
+                // a file left as it was if it would overflow the filesystem
+                let written = if redirect.append {
+                    fs::append(&redirect.file, out.as_bytes())
+                } else {
+                    fs::write(&redirect.file, out.as_bytes())
+                };
+                if let Err(err) = written {
+                    writeln!(Writer, "error: {}: {}", redirect.file, err).ok();
+                }
+            } else {
+                self.run(cmd, &args, stdin.as_deref(), &mut Writer);
+            }
+        }
+    }
+    /// Runs one built-in, writing its output to `out`. `stdin` is the output of the
+    /// previous command in a pipeline.
+    fn run(&mut self, cmd: &str, args: &[&str], stdin: Option<&str>, out: &mut dyn Write) {
+        match COMMANDS.iter().chain(HIDDEN_COMMANDS).find(|command| command.name() == cmd) {
+            Some(command) => (command.handler)(self, args, stdin, out),
+            None => { writeln!(out, "unknown: {}", cmd).ok(); }
+        }
+    }
+}
+
+// Built-in commands, see `COMMANDS`
+
+fn cmd_echo(_shell: &mut Shell, args: &[&str], stdin: Option<&str>, out: &mut dyn Write) {
+    if !args.is_empty() {
+        writeln!(out, "{}", args.join(" ")).ok();
+    } else if let Some(text) = stdin {
+        // with no arguments, pass piped input through
+        write!(out, "{}", text).ok();
+    }
+}
+
+fn cmd_clear(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first() {
+        None => screen::clear(),
+        Some(name) => match screen::Color::parse(name) {
+            Some(color) => if let Some(mut screen) = need_screen(out) { screen.clear_to_color(color) },
+            None => { writeln!(out, "unknown color {}; use a basic color name or #RRGGBB", name).ok(); }
+        },
+    }
+}
+
+fn cmd_ticks(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    writeln!(out, "{}", interrupts::current_ticks()).ok();
+}
+
+fn cmd_date(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    writeln!(out, "{}", rtc::now()).ok();
+}
+
+fn cmd_uptime(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    print_uptime(out, interrupts::current_ticks(), interrupts::timer_hz());
+}
+
+fn cmd_sleep(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first().and_then(|ms| ms.parse::<u64>().ok()) {
+        Some(ms) => sleep(ms),
+        None => { writeln!(out, "usage: sleep <ms>").ok(); }
+    }
+}
+
+fn cmd_memstat(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let snapshot = allocator::snapshot();
+    writeln!(out, "used: {} / {} bytes, peak {} bytes", snapshot.used, snapshot.total, snapshot.peak).ok();
+}
+
+fn cmd_stack(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let stack = kernel::stack();
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    if !stack.contains(&rsp) {
+        writeln!(out, "rsp {:#x} is outside the known stack {:#x}..{:#x}", rsp, stack.start, stack.end).ok();
+        return;
+    }
+    let (used, size) = (stack.end - rsp, stack.end - stack.start);
+    writeln!(out, "stack {:#x}..{:#x}, rsp {:#x}", stack.start, stack.end, rsp).ok();
+    writeln!(out, "used: {} / {} bytes ({}%), {} bytes left", used, size, used * 100 / size, rsp - stack.start).ok();
+}
+
+fn cmd_meminfo(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let info = memory::meminfo();
+    let kib = |bytes: u64| bytes / 1024;
+    writeln!(out, "total:        {:>10} KiB", kib(info.total)).ok();
+    writeln!(out, "reserved:     {:>10} KiB", kib(info.reserved)).ok();
+    writeln!(out, "bootloader:   {:>10} KiB, kernel image {} KiB", kib(info.bootloader), kib(info.kernel_image)).ok();
+    writeln!(out, "usable:       {:>10} KiB", kib(info.usable)).ok();
+    writeln!(out, "  allocated:  {:>10} KiB", kib(info.allocated)).ok();
+    writeln!(out, "  free:       {:>10} KiB", kib(info.free)).ok();
+    writeln!(out, "heap:         {:>10} KiB used of {} KiB", kib(info.heap_used), kib(info.heap_total)).ok();
+}
+
+fn cmd_allocstat(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let stats = allocator::alloc_stats();
+    writeln!(out, "live bytes:    {}", stats.live_bytes).ok();
+    writeln!(out, "peak bytes:    {}", stats.peak_bytes).ok();
+    writeln!(out, "total allocs:  {}", stats.total_allocs).ok();
+    writeln!(out, "total frees:   {}", stats.total_frees).ok();
+    writeln!(out, "failed allocs: {}", stats.failed_allocs).ok();
+}
+
+fn cmd_heapdump(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let summary = allocator::heap_dump();
+    writeln!(out, "{} free blocks, {} bytes free, largest {} bytes, {}% fragmented",
+        summary.free_blocks, summary.free_bytes, summary.largest_free, summary.fragmentation()).ok();
+    writeln!(out, "free block list written to serial").ok();
+}
+
+fn cmd_setfont(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first().copied() {
+        Some("big") => if let Some(mut screen) = need_screen(out) { screen.set_scale(2) },
+        Some("small") => if let Some(mut screen) = need_screen(out) { screen.set_scale(1) },
+        _ => { writeln!(out, "usage: setfont big|small").ok(); }
+    }
+}
+
+fn cmd_beep(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let freq = args.first().map_or(Some(880), |f| f.parse::<u32>().ok());
+    let ms = args.get(1).map_or(Some(200), |m| m.parse::<u64>().ok());
+    match (freq, ms) {
+        (Some(freq), Some(ms)) => {
+            speaker::start(freq);
+            sleep(ms.min(BEEP_MAX_MS));
+            speaker::stop();
+        }
+        _ => { writeln!(out, "usage: beep [hz] [ms]").ok(); }
+    }
+}
+
+fn cmd_bench(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first().copied() {
+        Some("alloc") => {
+            let start = interrupts::current_ticks();
+            let (start_ns, cycles) = (time::now_ns(), time::rdtsc());
+            let failed = alloc_workload(BENCH_ALLOCS);
+            let (ns, cycles) = (time::now_ns() - start_ns, time::rdtsc() - cycles);
+            let ticks = interrupts::current_ticks() - start;
+            write!(out, "{} allocations in {} ticks ({} ns, {} cycles)", BENCH_ALLOCS, ticks, ns, cycles).ok();
+            match BENCH_ALLOCS.checked_div(ticks as usize) {
+                Some(rate) => writeln!(out, ", {} per tick", rate),
+                None => writeln!(out, ", under a tick"),
+            }.ok();
+            if ns > 0 {
+                writeln!(out, "{} ns per allocation{}", ns / BENCH_ALLOCS as u64,
+                    if time::is_precise() { "" } else { " (timer resolution only, no invariant TSC)" }).ok();
+            }
+            if failed > 0 {
+                writeln!(out, "{} allocations failed", failed).ok();
+            }
+        }
+        Some("scroll") => {
+            let Some((_, _, _, rows)) = need_screen(out).map(|screen| screen.dimensions()) else { return };
+            // fill the screen first, so every timed line scrolls
+            for _ in 0..rows {
+                writeln!(Writer).ok();
+            }
+            let time_lines = |fast| {
+                if let Some(mut screen) = screenwriter() {
+                    screen.set_fast_scroll(fast);
+                }
+                let start = time::now_ns();
+                for line in 0..BENCH_LINES {
+                    writeln!(Writer, "scroll benchmark line {}", line).ok();
+                }
+                (time::now_ns() - start) / BENCH_LINES as u64
+            };
+            let redraw = time_lines(false);
+            let copy = time_lines(true);
+            writeln!(out, "per line: {} ns copying rows, {} ns repainting{}", copy, redraw,
+                if time::is_precise() { "" } else { " (timer resolution only, no invariant TSC)" }).ok();
+            if copy > 0 {
+                writeln!(out, "copying is {}.{}x as fast", redraw / copy, redraw * 10 / copy % 10).ok();
+            }
+        }
+        _ => { writeln!(out, "usage: bench alloc|scroll").ok(); }
+    }
+}
+
+fn cmd_statusbar(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first().copied() {
+        Some("on") => if let Some(mut screen) = need_screen(out) { screen.set_status_bar(true) },
+        Some("off") => if let Some(mut screen) = need_screen(out) { screen.set_status_bar(false) },
+        _ => { writeln!(out, "usage: statusbar on|off").ok(); }
+    }
+}
+
+fn cmd_cursor(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first().copied() {
+        Some("on") => if let Some(mut screen) = need_screen(out) { screen.set_cursor_visible(true) },
+        Some("off") => if let Some(mut screen) = need_screen(out) { screen.set_cursor_visible(false) },
+        _ => { writeln!(out, "usage: cursor on|off").ok(); }
+    }
+}
+
+fn cmd_at(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args {
+        [col, row, text @ ..] => match (col.parse::<usize>(), row.parse::<usize>()) {
+            (Ok(col), Ok(row)) => if let Some(mut screen) = need_screen(out) {
+                screen.write_at(col, row, format_args!("{}", text.join(" ")));
+            },
+            _ => { writeln!(out, "usage: at <col> <row> <text...>").ok(); }
+        },
+        _ => { writeln!(out, "usage: at <col> <row> <text...>").ok(); }
+    }
+}
+
+fn cmd_region(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let rows = match args {
+        ["off"] => Some((0, usize::MAX)),
+        [top, bottom] => match (top.parse::<usize>(), bottom.parse::<usize>()) {
+            (Ok(top), Ok(bottom)) if top <= bottom => Some((top, bottom)),
+            _ => None,
+        },
+        _ => None,
+    };
+    match rows {
+        Some((top, bottom)) => if let Some(mut screen) = need_screen(out) { screen.scroll_region(top, bottom) },
+        None => { writeln!(out, "usage: region <top> <bottom>|off").ok(); }
+    }
+}
+
+fn cmd_resolution(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    if let Some((width, height, cols, rows)) = need_screen(out).map(|screen| screen.dimensions()) {
+        writeln!(out, "{}x{} pixels, {}x{} characters", width, height, cols, rows).ok();
+    }
+}
+
+fn cmd_selftest(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    selftest::run(out);
+}
+
+fn cmd_cpuinfo(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    fn text(bytes: &[u8]) -> &str {
+        core::str::from_utf8(bytes).unwrap_or("?").trim_matches([' ', '\0'])
+    }
+    let signature = cpuid::signature();
+    writeln!(out, "vendor:   {}", text(&cpuid::vendor())).ok();
+    writeln!(out, "family {:#x}, model {:#x}, stepping {}", signature.family, signature.model, signature.stepping).ok();
+    match cpuid::brand() {
+        Some(brand) => { writeln!(out, "brand:    {}", text(&brand)).ok(); }
+        None => { writeln!(out, "brand:    (not reported)").ok(); }
+    }
+    write!(out, "features:").ok();
+    for feature in cpuid::Feature::ALL {
+        write!(out, " {}{}", if cpuid::has(feature) { "+" } else { "-" }, feature.name()).ok();
+    }
+    writeln!(out).ok();
+}
+
+fn cmd_apic(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    use interrupts::APICOffset;
+    let registers = [
+        ("id", APICOffset::Ir),
+        ("version", APICOffset::Vr),
+        ("task priority", APICOffset::Tpr),
+        ("spurious vector", APICOffset::Svr),
+        ("lvt timer", APICOffset::LvtT),
+        ("lvt lint0", APICOffset::LvtLint0),
+        ("lvt lint1", APICOffset::LvtLint1),
+        ("lvt error", APICOffset::LvtE),
+        ("timer initial", APICOffset::Ticr),
+        ("timer current", APICOffset::Tccr),
+        ("timer divide", APICOffset::Tdcr),
+    ];
+    if interrupts::read_lapic(APICOffset::Ir).is_none() {
+        writeln!(out, "no local APIC in use, interrupts go through the 8259 PIC").ok();
+    }
+    for (name, register) in registers {
+        if let Some(value) = interrupts::read_lapic(register) {
+            writeln!(out, "{:<16} {:#010x}", name, value).ok();
+        }
+    }
+    writeln!(out, "{:<16} {}", "spurious", interrupts::spurious_interrupts()).ok();
+}
+
+fn cmd_msgbox(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    if args.is_empty() {
+        writeln!(out, "usage: msgbox <text>").ok();
+        return;
+    }
+    // not locked while waiting, or no key would ever come
+    let Some(snapshot) = need_screen(out).map(|mut screen| screen.message_box(&args.join(" "))) else { return };
+    wait_key();
+    if let Some(mut screen) = screenwriter() {
+        screen.restore_region(snapshot);
+    }
+}
+
+fn cmd_calc(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    if args.is_empty() {
+        writeln!(out, "usage: calc <expression>").ok();
+        return;
+    }
+    match calc::evaluate(&args.join(" ")) {
+        Ok(value) => { writeln!(out, "{}", value).ok(); }
+        Err(err) => { writeln!(out, "calc: {}", err).ok(); }
+    }
+}
+
+fn cmd_progress(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let hz = interrupts::timer_hz().max(1);
+    let start = interrupts::current_ticks();
+    let duration = PROGRESS_SECONDS * hz;
+    let mut shown = None;
+    loop {
+        let elapsed = interrupts::current_ticks().wrapping_sub(start).min(duration);
+        let percent = elapsed * 100 / duration;
+        if shown != Some(percent) {
+            // back to the start of the row, then draw over the previous bar
+            let filled = (elapsed * PROGRESS_WIDTH as u64 / duration) as usize;
+            write!(out, "\r[{:#<filled$}{:<rest$}] {:>3}%", "", "", percent, rest = PROGRESS_WIDTH - filled).ok();
+            shown = Some(percent);
+        }
+        if elapsed == duration {
+            writeln!(out).ok();
+            return;
+        }
+        if keyboard::discard_through(DecodedKey::Unicode('\u{3}')) {
+            writeln!(out, " ^C").ok();
+            return;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+fn cmd_motd(shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    shell.print_motd(out);
+}
+
+fn cmd_brk(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    writeln!(out, "executing int3...").ok();
+    x86_64::instructions::interrupts::int3();
+    writeln!(out, "resumed after the breakpoint").ok();
+}
+
+fn cmd_panic(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, _out: &mut dyn Write) {
+    panic!("test panic from the shell")
+}
+
+#[cfg(debug_assertions)]
+fn cmd_overflow(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    writeln!(out, "overflowing the kernel stack...").ok();
+    overflow_stack(0);
+}
+
+fn cmd_framestat(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let (used, total) = frame_allocator::with_frames(|frames| (frames.frames_used(), frames.frames_total()));
+    writeln!(out, "frames used: {} / {} ({} KiB / {} KiB)", used, total, used * 4, total * 4).ok();
+}
+
+fn cmd_pmm(shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args {
+        ["alloc"] => match frame_allocator::with_frames(|frames| frames.allocate_frame()) {
+            Some(frame) => {
+                shell.pmm_frames.push(frame);
+                writeln!(out, "allocated frame {:#x}", frame.start_address().as_u64()).ok();
+            }
+            None => { writeln!(out, "pmm: out of frames").ok(); }
+        },
+        ["free", addr] => match parse_hex(addr) {
+            Some(addr) if addr % 4096 != 0 => { writeln!(out, "pmm: {:#x} isn't the start of a frame", addr).ok(); }
+            Some(addr) => {
+                let frame = PhysFrame::containing_address(PhysAddr::new(addr));
+                match shell.pmm_frames.iter().position(|&f| f == frame) {
+                    Some(i) => match frame_allocator::with_frames(|frames| frames.try_free(frame)) {
+                        Ok(()) => {
+                            shell.pmm_frames.swap_remove(i);
+                            writeln!(out, "freed frame {:#x}", addr).ok();
+                        }
+                        Err(err) => { writeln!(out, "pmm: can't free {:#x}: {}", addr, err).ok(); }
+                    },
+                    None => { writeln!(out, "pmm: {:#x} wasn't allocated with pmm alloc", addr).ok(); }
+                }
+            }
+            None => { writeln!(out, "usage: pmm free <hexaddr>").ok(); }
+        },
+        _ => { writeln!(out, "usage: pmm alloc|free <hexaddr>").ok(); }
+    }
+}
+
+fn cmd_demand(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let Some(addr) = args.first().and_then(|a| parse_hex(a)) else {
+        writeln!(out, "usage: demand <hexaddr>").ok();
+        return;
+    };
+    let addr = addr & !7;
+    match demand::reserve(addr) {
+        Ok(_) if demand::is_mapped(addr) => { writeln!(out, "page at {:#x} is mapped already", addr).ok(); }
+        Ok(_) => { writeln!(out, "reserved page at {:#x}, reading it", addr).ok(); }
+        Err(err) => {
+            writeln!(out, "demand: {}", err).ok();
+            return;
+        }
+    }
+    let faults = demand::faults();
+    let value = unsafe { (addr as *const u64).read_volatile() };
+    writeln!(out, "read {:#x} at {:#x}, {} page faults handled", value, addr, demand::faults() - faults).ok();
+}
+
+fn cmd_lsmem(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    writeln!(out, "{:>14} {:>14} {:>12}  kind", "start", "end", "size").ok();
+    let mut usable = 0;
+    for region in memory::regions() {
+        let size = region.end - region.start;
+        writeln!(out, "{:#14x} {:#14x} {:>12}  {:?}", region.start, region.end, size, region.kind).ok();
+        if region.kind == MemoryRegionKind::Usable {
+            usable += size;
+        }
+    }
+    writeln!(out, "{} bytes usable in {} regions", usable, memory::regions().len()).ok();
+}
+
+fn cmd_hexdump(_shell: &mut Shell, args: &[&str], stdin: Option<&str>, out: &mut dyn Write) {
+    match args {
+        ["--addr", addr, len] => match (parse_hex(addr), len.parse::<u64>()) {
+            (Some(addr), Ok(len)) => peek(out, addr, len.min(PEEK_MAX)),
+            _ => { writeln!(out, "usage: hexdump --addr <hexaddr> <len>").ok(); }
+        },
+        ["--addr", ..] => { writeln!(out, "usage: hexdump --addr <hexaddr> <len>").ok(); }
+        [] => hexdump(out, 0, stdin.unwrap_or("").as_bytes()),
+        text => hexdump(out, 0, text.join(" ").as_bytes()),
+    }
+}
+
+fn cmd_peek(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let addr = args.first().and_then(|a| parse_hex(a));
+    let len = args.get(1).map_or(Some(16), |l| l.parse::<u64>().ok());
+    match (addr, len) {
+        (Some(addr), Some(len)) => peek(out, addr, len.min(PEEK_MAX)),
+        _ => { writeln!(out, "usage: peek <hexaddr> [len]").ok(); }
+    }
+}
+
+fn cmd_poke(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let addr = args.first().and_then(|a| parse_hex(a));
+    let byte = args.get(1).and_then(|b| parse_hex(b)).and_then(|b| u8::try_from(b).ok());
+    match (addr, byte) {
+        (Some(addr), Some(byte)) => match memory::phys_to_virt(addr, 1) {
+            Some(ptr) => unsafe { ptr.write_volatile(byte) },
+            None => { writeln!(out, "{:#x} is outside the memory map", addr).ok(); }
+        },
+        _ => { writeln!(out, "usage: poke <hexaddr> <hexbyte>").ok(); }
+    }
+}
+
+fn cmd_memtest(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let (Some(addr), Some(len)) = (args.first().and_then(|a| parse_hex(a)), args.get(1).and_then(|l| l.parse::<u64>().ok())) else {
+        writeln!(out, "usage: memtest <hexaddr> <len>").ok();
+        return;
+    };
+    let Some(end) = addr.checked_add(len).filter(|_| len > 0) else {
+        writeln!(out, "memtest: empty or overflowing range").ok();
+        return;
+    };
+    // claiming the frames keeps the heap, page tables and the kernel out of the way
+    if let Err(reason) = frame_allocator::with_frames(|frames| frames.claim(addr..end)) {
+        writeln!(out, "memtest: {:#x}..{:#x} is {}", addr, end, reason).ok();
+        return;
+    }
+    let first = PhysFrame::containing_address(PhysAddr::new(addr));
+    let frames = (end.div_ceil(4096) - addr / 4096) as usize;
+    let errors = memory::phys_to_virt(addr, len).map(|ptr| {
+        let memory = unsafe { slice::from_raw_parts_mut(ptr, len as usize) };
+        let mut shown = 0;
+        memory::walking_ones(memory, |offset, expected, found| {
+            if shown < MEMTEST_SHOWN {
+                writeln!(out, "  {:#x}: wrote {:#04x}, read {:#04x}", addr + offset as u64, expected, found).ok();
+                shown += 1;
+            }
+        })
+    });
+    frame_allocator::with_frames(|allocator| allocator.free_contiguous(first, frames));
+    match errors {
+        Some(0) => writeln!(out, "memtest: pass, {} bytes tested", len).ok(),
+        Some(errors) => writeln!(out, "memtest: FAIL, {} mismatches in {} bytes", errors, len).ok(),
+        None => writeln!(out, "memtest: {:#x}..{:#x} spans more than one memory region", addr, end).ok(),
+    };
+}
+
+fn cmd_port(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let port = |text: &str| parse_hex(text).and_then(|port| u16::try_from(port).ok());
+    match args {
+        ["read", number] => match port(number) {
+            Some(number) => {
+                let value: u8 = unsafe { Port::new(number).read() };
+                writeln!(out, "{:#06x}: {:#04x}", number, value).ok();
+            }
+            None => { writeln!(out, "port numbers go from 0 to ffff").ok(); }
+        },
+        ["write", number, byte, rest @ ..] => {
+            let byte = parse_hex(byte).and_then(|byte| u8::try_from(byte).ok());
+            match (port(number), byte) {
+                (Some(number), Some(byte)) if rest == ["--danger"] => unsafe { Port::new(number).write(byte) },
+                (Some(_), Some(_)) => { writeln!(out, "port writes can hang the machine; add --danger to go ahead").ok(); }
+                _ => { writeln!(out, "usage: port write <hexport> <hexbyte> --danger").ok(); }
+            }
+        }
+        _ => { writeln!(out, "usage: port read <hexport> | write <hexport> <hexbyte> --danger").ok(); }
+    }
+}
+
+fn cmd_dmesg(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let log = log::with_dmesg(|older, newer| [older, newer].concat());
+    write!(out, "{}", String::from_utf8_lossy(&log)).ok();
+}
+
+fn cmd_loglevel(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first() {
+        None => { writeln!(out, "{}", log::level()).ok(); }
+        Some(name) => match log::Level::parse(name) {
+            Some(level) => log::set_level(level),
+            None => { writeln!(out, "usage: loglevel debug|info|warn|error").ok(); }
+        },
+    }
+}
+
+fn cmd_keymap(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first().copied() {
+        Some("us") => keyboard::set_layout(keyboard::Layout::Us),
+        Some("de") => keyboard::set_layout(keyboard::Layout::De),
+        _ => { writeln!(out, "usage: keymap us|de").ok(); }
+    }
+}
+
+fn cmd_mirror(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first().copied() {
+        Some("on") => screen::set_mirror_serial(true),
+        Some("off") => screen::set_mirror_serial(false),
+        _ => { writeln!(out, "usage: mirror on|off").ok(); }
+    }
+}
+
+fn cmd_usertest(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match user::test() {
+        Ok(value) => { writeln!(out, "back from ring 3 with {}", value).ok(); }
+        Err(err) => { writeln!(out, "usertest failed: {}", err).ok(); }
+    }
+    match user::syscall_test() {
+        Ok(code) => { writeln!(out, "ring 3 program exited with {}", code).ok(); }
+        Err(err) => { writeln!(out, "syscall test failed: {}", err).ok(); }
+    }
+}
+
+fn cmd_conv(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let bases = (args.get(1).and_then(|b| b.parse().ok()), args.get(2).and_then(|b| b.parse().ok()));
+    match (args.first(), bases) {
+        (Some(value), (Some(from), Some(to))) if args.len() == 3 => match conv::convert(value, from, to) {
+            Ok(text) => { writeln!(out, "{}", text).ok(); }
+            Err(err) => { writeln!(out, "conv: {}", err).ok(); }
+        },
+        _ => { writeln!(out, "usage: conv <value> <from-base> <to-base>").ok(); }
+    }
+}
+
+fn cmd_spawn(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let first = task::spawn(count_task);
+    let second = task::spawn(count_task);
+    writeln!(out, "started tasks {} and {}, {} running", first, second, task::count()).ok();
+}
+
+fn cmd_irqdemo(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    // ticks don't come in while the lock is held, so time it with the TSC
+    let cycles = time::tsc_hz().map_or(IRQDEMO_CYCLES, |hz| hz / 5);
+    let (before, during) = {
+        let ticks = DEMO_TICKS.lock();
+        let before = *ticks;
+        let start = time::rdtsc();
+        while time::rdtsc().wrapping_sub(start) < cycles {
+            core::hint::spin_loop();
+        }
+        (before, *ticks)
+    };
+    sleep(100);
+    let after = *DEMO_TICKS.lock();
+    writeln!(out, "while held: {} ticks counted, {} once released for 100 ms", during - before, after - during).ok();
+}
+
+fn cmd_reboot(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    writeln!(out, "rebooting...").ok();
+    power::reboot();
+}
+
+fn cmd_shutdown(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    writeln!(out, "shutting down...").ok();
+    power::shutdown();
+}
+
+fn cmd_ls(_shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let rows: Vec<Vec<String>> = fs::list().into_iter()
+        .map(|(name, size)| vec![name, format!("{} bytes", size)])
+        .collect();
+    table::print(out, &rows);
+    writeln!(out, "{} / {} bytes used", fs::bytes_used(), fs::MAX_BYTES).ok();
+}
+
+fn cmd_cat(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let [name] = args else {
+        writeln!(out, "usage: cat <name>").ok();
+        return;
+    };
+    match fs::read(name) {
+        Ok(data) => match core::str::from_utf8(&data) {
+            Ok(text) => { write!(out, "{}", text).ok(); }
+            Err(_) => hexdump(out, 0, &data),
+        },
+        Err(err) => { writeln!(out, "cat: {}: {}", name, err).ok(); }
+    }
+}
+
+fn cmd_write(_shell: &mut Shell, args: &[&str], stdin: Option<&str>, out: &mut dyn Write) {
+    let Some((name, text)) = args.split_first() else {
+        writeln!(out, "usage: write <name> [text...]").ok();
+        return;
+    };
+    let data = match (text, stdin) {
+        ([], Some(piped)) => String::from(piped),
+        (text, _) => format!("{}\n", text.join(" ")),
+    };
+    if let Err(err) = fs::write(name, data.as_bytes()) {
+        writeln!(out, "write: {}: {}", name, err).ok();
+    }
+}
+
+fn cmd_rm(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let [name] = args else {
+        writeln!(out, "usage: rm <name>").ok();
+        return;
+    };
+    if let Err(err) = fs::remove(name) {
+        writeln!(out, "rm: {}: {}", name, err).ok();
+    }
+}
+
+fn cmd_prompt(shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args {
+        [] => { writeln!(out, "{}", shell.prompt).ok(); }
+        args => {
+            let template = args.join(" ");
+            // the line editor counts one column per character, which a control character
+            // or escape sequence doesn't take
+            if template.chars().any(char::is_control) {
+                writeln!(out, "prompt: the template can't hold control characters").ok();
+                return;
+            }
+            shell.prompt = if template.is_empty() { String::from(DEFAULT_PROMPT) } else { template };
+        }
+    }
+}
+
+fn cmd_set(shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args {
+        [] => for (name, value) in &shell.vars {
+            writeln!(out, "{}={}", name, value).ok();
+        },
+        [assignment] => match assignment.split_once('=') {
+            Some((name, value)) => if let Err(err) = shell.set_var(name, value) {
+                writeln!(out, "set: {}", err).ok();
+            },
+            None => { writeln!(out, "usage: set <name>=<value>").ok(); }
+        },
+        _ => { writeln!(out, "usage: set <name>=<value>").ok(); }
+    }
+}
+
+fn cmd_read(shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    let line = read_line("? ");
+    match args.first() {
+        Some(name) => if let Err(err) = shell.set_var(name, &line) {
+            writeln!(out, "read: {}", err).ok();
+        },
+        None => { writeln!(out, "{}", line).ok(); }
+    }
+}
+
+fn cmd_history(shell: &mut Shell, _args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    for (i, command) in shell.history.iter().enumerate() {
+        writeln!(out, "{:>4}  {}", shell.history_first + i, command).ok();
+    }
+}
+
+fn cmd_help(_shell: &mut Shell, args: &[&str], _stdin: Option<&str>, out: &mut dyn Write) {
+    match args.first() {
+        None => {
+            writeln!(out, "Built-ins:").ok();
+            for command in COMMANDS {
+                writeln!(out, "  \x1b[33m{:<15}\x1b[0m - {}", command.usage, command.summary).ok();
+            }
+        }
+        Some(name) => match COMMANDS.iter().find(|command| command.name() == *name) {
+            Some(command) => { writeln!(out, "usage: {}\n{}", command.usage, command.details).ok(); }
+            None => { writeln!(out, "help: no command named {}", name).ok(); }
+        },
+    }
+}
+
+/// Prints `ticks` timer ticks at `hz` as hours:minutes:seconds, with as many decimals
+/// as one tick needs. Everything is derived by division, so no tick count overflows;
+/// the counter itself only wraps after billions of years.
+fn print_uptime(out: &mut dyn Write, ticks: u64, hz: u64) {
+    let seconds = ticks / hz;
+    let (mut digits, mut scale) = (0, 1);
+    while scale < hz {
+        scale *= 10;
+        digits += 1;
+    }
+    write!(out, "up {}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60).ok();
+    if digits > 0 {
+        write!(out, ".{:0width$}", ticks % hz * scale / hz, width = digits).ok();
+    }
+    writeln!(out).ok();
+}
+
+/// Most mismatches `memtest` lists; the rest are only counted
+const MEMTEST_SHOWN: usize = 16;
+
+/// Most bytes `peek` shows at once
+const PEEK_MAX: u64 = 256;
+
+/// Parses a hexadecimal number, with or without a `0x` prefix.
+fn parse_hex(text: &str) -> Option<u64> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/// Prints a hex dump of `len` bytes of physical memory starting at `addr`.
+fn peek(out: &mut dyn Write, addr: u64, len: u64) {
+    let Some(ptr) = memory::phys_to_virt(addr, len) else {
+        writeln!(out, "{:#x}..{:#x} is outside the memory map", addr, addr.saturating_add(len)).ok();
+        return;
+    };
+    let bytes = unsafe { slice::from_raw_parts(ptr, len as usize) };
+    hexdump(out, addr, bytes);
+}
+
+/// Writes `bytes` as a hex dump: 16 bytes a line, each line starting with the offset
+/// of its first byte (counting from `start`) and ending with the printable ASCII
+/// characters among them.
+fn hexdump(out: &mut dyn Write, start: u64, bytes: &[u8]) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        write!(out, "{:08x}:", start + i as u64 * 16).ok();
+        for byte in line {
+            write!(out, " {:02x}", byte).ok();
+        }
+        let text: String = line.iter()
+            .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+            .collect();
+        writeln!(out, "{:width$}  {}", "", text, width = (16 - line.len()) * 3).ok();
+    }
+}
+
+/// The locked screen, or `None` after telling the user there is only serial.
+fn need_screen(out: &mut dyn Write) -> Option<ScreenGuard> {
+    let screen = screenwriter();
+    if screen.is_none() {
+        writeln!(out, "no screen, only the serial console").ok();
+    }
+    screen
+}
+
+/// How long `progress` takes to fill its bar, and how many characters wide the bar is
+const PROGRESS_SECONDS: u64 = 2;
+const PROGRESS_WIDTH: usize = 40;
+
+/// Longest tone `beep` plays
+const BEEP_MAX_MS: u64 = 5000;
+
+/// TSC cycles `irqdemo` holds `DEMO_TICKS` for when the TSC rate isn't known
+const IRQDEMO_CYCLES: u64 = 500_000_000;
+
+/// Counted up by the timer interrupt, and held by `irqdemo`
+pub static DEMO_TICKS: IrqMutex<u64> = IrqMutex::new(0);
+
+/// Allocations made by `bench alloc`
+const BENCH_ALLOCS: usize = 10_000;
+
+/// Lines `bench scroll` prints for each way of scrolling
+const BENCH_LINES: usize = 100;
+
+/// Allocates and frees `count` blocks of mixed sizes (8 bytes to 2 KiB, from a fixed
+/// pseudo-random sequence), keeping up to 16 alive at a time so frees come in a mixed
+/// order too. Returns how many allocations failed.
+fn alloc_workload(count: usize) -> usize {
+    use alloc::alloc::{alloc, dealloc, Layout};
+    let mut live: [Option<(*mut u8, Layout)>; 16] = [None; 16];
+    let mut seed: u32 = 0x1234_5678;
+    let mut failed = 0;
+    for _ in 0..count {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let slot = (seed >> 8) as usize % live.len();
+        let size = 8 << ((seed >> 16) % 9);
+        if let Some((ptr, layout)) = live[slot].take() {
+            unsafe { dealloc(ptr, layout) };
+        }
+        let layout = Layout::from_size_align(size, 8).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() {
+            failed += 1;
+        } else {
+            live[slot] = Some((ptr, layout));
+        }
+    }
+    for (ptr, layout) in live.iter().flatten() {
+        unsafe { dealloc(*ptr, *layout) };
+    }
+    failed
+}
+
+/// Expands the `prompt` template: `\t` becomes the uptime as h:mm:ss, `\m` the heap
+/// in use in KiB and `\\` a backslash. Anything else is kept as it is.
+fn render_prompt(template: &str) -> String {
+    let mut prompt = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('t')) => {
+                chars.next();
+                let seconds = interrupts::current_ticks() / interrupts::timer_hz().max(1);
+                write!(prompt, "{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60).ok();
+            }
+            ('\\', Some('m')) => {
+                chars.next();
+                write!(prompt, "{}K", allocator::snapshot().used / 1024).ok();
+            }
+            ('\\', Some('\\')) => {
+                chars.next();
+                prompt.push('\\');
+            }
+            (c, _) => prompt.push(c),
+        }
+    }
+    prompt
+}
+
+/// Message of the day: the kernel name, its version from Cargo and the build date
+fn motd() -> String {
+    format!("{} {}, built {}\nType `help` for a list of commands.",
+        env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("BUILD_DATE"))
+}
+
+/// Waits for the next key and returns it.
+///
+/// For commands, which run from the main loop with `SHELL` locked: the key is taken
+/// straight from the keyboard queue instead of going through `Shell::handle_key`, which
+/// would wait for the lock forever.
+pub fn wait_key() -> DecodedKey {
+    loop {
+        if let Some(key) = keyboard::pop_key() {
+            return key;
+        }
+        // as in the main loop, so a key arriving between the check and the halt wakes it
+        x86_64::instructions::interrupts::disable();
+        if keyboard::has_keys() {
+            x86_64::instructions::interrupts::enable();
+        } else {
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
+}
+
+/// Prints `prompt` and returns the next line typed, without the newline. Keys come from
+/// `wait_key`; only plain characters and Backspace are handled.
+pub fn read_line(prompt: &str) -> String {
+    write!(Writer, "{}", prompt).ok();
+    let mut line = String::new();
+    loop {
+        match wait_key() {
+            DecodedKey::Unicode('\n') => {
+                writeln!(Writer).ok();
+                return line;
+            }
+            DecodedKey::Unicode('\u{8}') | DecodedKey::RawKey(KeyCode::Backspace) => {
+                if line.pop().is_some() {
+                    write!(Writer, "\x08 \x08").ok();
+                }
+            }
+            DecodedKey::Unicode(c) if !c.is_control() => {
+                line.push(c);
+                write!(Writer, "{}", c).ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Waits at least `ms` milliseconds, rounded up to whole timer ticks, halting until
+/// each tick. Commands run from the main loop, with interrupts enabled.
+fn sleep(ms: u64) {
+    let start = interrupts::current_ticks();
+    let wait = ms.saturating_mul(interrupts::timer_hz()).div_ceil(1000);
+    while interrupts::current_ticks().wrapping_sub(start) < wait {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Task for `spawn`: prints a counter twice a second, yielding while it waits.
+fn count_task() {
+    let id = task::current();
+    for i in 0..5 {
+        // one write, so the line goes out under one hold of the screen lock
+        Writer.write_str(&format!("task {}: {}\n", id, i)).ok();
+        let start = interrupts::current_ticks();
+        while interrupts::current_ticks().wrapping_sub(start) < interrupts::timer_hz() / 2 {
+            task::yield_now();
+        }
+    }
+}
+
+/// Recurses until the kernel stack runs out, to check that the double fault handler
+/// catches it on its own stack.
+#[cfg(debug_assertions)]
+#[allow(unconditional_recursion)]
+fn overflow_stack(depth: u64) -> u64 {
+    let frame = [depth; 8];
+    overflow_stack(core::hint::black_box(frame)[0] + 1) + 1
+}
+
+lazy_static! {
+    // A plain Mutex, not an IrqMutex: commands run with it held and need the timer and
+    // keyboard interrupts (`sleep`, `read`). Interrupt handlers never lock it; the timer
+    // only checks `is_locked`, and keys just go into the queue.
+    pub static ref SHELL: Mutex<Shell> = Mutex::new(Shell::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_lines() {
+        let mut out = String::new();
+        hexdump(&mut out, 0x1000, b"Hello, world!\n\x00\x7f~ ok");
+        assert_eq!(out, "\
+00001000: 48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 0a 00 7f  Hello, world!...
+00001010: 7e 20 6f 6b                                      ~ ok
+");
+        out.clear();
+        hexdump(&mut out, 0, b"");
+        assert_eq!(out, "");
+    }
+
+    #[test]
+    fn prompt_templates_without_control_characters() {
+        assert_eq!(render_prompt(r"a\\b \x> "), r"a\b \x> ");
+        let mut shell = Shell::new();
+        let mut out = String::new();
+        cmd_prompt(&mut shell, &["\x1b[31m>"], None, &mut out);
+        assert!(out.contains("control characters"));
+        assert_eq!(shell.prompt, DEFAULT_PROMPT);
+        cmd_prompt(&mut shell, &["$"], None, &mut out);
+        assert_eq!(shell.prompt, "$");
+    }
+}
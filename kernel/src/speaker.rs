@@ -0,0 +1,31 @@
+//! PC speaker, driven by PIT channel 2 through the gate bits of port 0x61.
+
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use crate::interrupts::PIT_HZ;
+
+/// Starts a square wave of about `freq` Hz (clamped to 19..=20000). Without a speaker
+/// the port writes go nowhere, so this is harmless.
+pub fn start(freq: u32) {
+    let divisor = PIT_HZ / u64::from(freq.clamp(19, 20_000));
+    without_interrupts(|| unsafe {
+        // channel 2, lobyte/hibyte, mode 3 (square wave)
+        Port::<u8>::new(0x43).write(0b1011_0110);
+        let mut channel2 = Port::<u8>::new(0x42);
+        channel2.write(divisor as u8);
+        channel2.write((divisor >> 8) as u8);
+        // bit 0 gates channel 2, bit 1 connects its output to the speaker
+        let mut gate = Port::<u8>::new(0x61);
+        let control = gate.read();
+        gate.write(control | 0b11);
+    });
+}
+
+/// Silences the speaker.
+pub fn stop() {
+    without_interrupts(|| unsafe {
+        let mut gate = Port::<u8>::new(0x61);
+        let control = gate.read();
+        gate.write(control & !0b11);
+    });
+}
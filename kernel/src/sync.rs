@@ -0,0 +1,133 @@
+//! Locks shared with interrupt handlers.
+
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+#[cfg(target_os = "none")]
+use x86_64::instructions::interrupts;
+
+/// Stands in for the interrupt flag in host unit tests, where `cli` faults
+#[cfg(not(target_os = "none"))]
+mod interrupts {
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+
+    pub fn are_enabled() -> bool {
+        ENABLED.load(Ordering::Relaxed)
+    }
+
+    pub fn disable() {
+        ENABLED.store(false, Ordering::Relaxed);
+    }
+
+    pub fn enable() {
+        ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A `spin::Mutex` that disables interrupts for as long as it's held, so an interrupt
+/// handler taking the same lock can't spin forever on the code it interrupted. The
+/// interrupt flag goes back to what it was when the guard is dropped, so nested locks
+/// only turn interrupts back on once the outermost one is released.
+///
+/// Only for short critical sections: nothing that waits for a timer tick or a key press
+/// may run while one is held.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    // dropped by hand, so the lock is released before interrupts come back on
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    enable: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+
+    /// Disables interrupts and takes the lock, spinning until it's free.
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard { guard: ManuallyDrop::new(self.inner.lock()), enable }
+    }
+
+    /// Takes the lock if it's free, with interrupts disabled until the guard is dropped.
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enable = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard { guard: ManuallyDrop::new(guard), enable }),
+            None => {
+                if enable {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enable {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one test, since the stand-in interrupt flag is shared by everything in the crate
+    #[test]
+    fn guards_put_the_interrupt_flag_back() {
+        let lock = IrqMutex::new(0);
+        let other = IrqMutex::new(0);
+
+        interrupts::enable();
+        let outer = lock.lock();
+        assert!(!interrupts::are_enabled());
+        let inner = other.lock();
+        drop(inner);
+        assert!(!interrupts::are_enabled(), "only the outermost guard turns interrupts back on");
+        assert!(lock.try_lock().is_none());
+        assert!(!interrupts::are_enabled());
+        drop(outer);
+        assert!(interrupts::are_enabled());
+
+        assert!(lock.try_lock().is_some());
+        assert!(interrupts::are_enabled());
+        let held = lock.inner.lock();
+        assert!(lock.try_lock().is_none());
+        assert!(interrupts::are_enabled(), "a failed try_lock leaves the flag as it was");
+        drop(held);
+
+        interrupts::disable();
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    }
+}
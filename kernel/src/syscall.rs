@@ -0,0 +1,145 @@
+use core::arch::global_asm;
+use core::fmt::Write;
+use kernel::gdt;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{PageTableFlags, Translate};
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::VirtAddr;
+use crate::frame_allocator::with_mapper;
+use crate::screen::Writer;
+
+/// `write(ptr, len)`: prints `len` bytes of UTF-8 at `ptr` on screen, returns `len`
+pub const SYS_WRITE: u64 = 0;
+/// `exit(code)`: leaves ring 3; `usermode::enter_user` returns `code`
+pub const SYS_EXIT: u64 = 1;
+
+/// Takes the three arguments from rdi, rsi and rdx, returns the value for rax
+type Handler = fn(u64, u64, u64) -> u64;
+
+/// Handlers by system call number; `SYS_EXIT` never gets here, the entry stub handles it
+const SYSCALLS: &[(u64, Handler)] = &[(SYS_WRITE, sys_write)];
+
+/// Returned for unknown system calls and bad arguments
+const ERROR: u64 = u64::MAX;
+
+const STACK_SIZE: usize = 4096 * 4;
+
+/// Kernel stack for system calls, aligned so that `syscall_dispatch` gets called with
+/// the 16-byte alignment the ABI promises it
+#[repr(align(16))]
+struct SyscallStack([u8; STACK_SIZE]);
+
+static mut SYSCALL_STACK: SyscallStack = SyscallStack([0; STACK_SIZE]);
+/// Top of `SYSCALL_STACK`, loaded by the entry stub
+static mut SYSCALL_STACK_TOP: u64 = 0;
+/// User stack pointer while a system call runs
+static mut USER_RSP: u64 = 0;
+
+// `syscall` leaves the user rip in rcx and rflags in r11 and doesn't switch stacks, so
+// the stub moves to SYSCALL_STACK, saves every register but rax (the result), and
+// calls syscall_dispatch(number, arg1, arg2, arg3) with the arguments from rdi, rsi
+// and rdx. `exit` goes straight to usermode's user_return instead of back to ring 3.
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {stack_top}]",
+    "cmp rax, {exit}",
+    "je 2f",
+    "push rcx",
+    "push r11",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {dispatch}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r11",
+    "pop rcx",
+    "mov rsp, [rip + {user_rsp}]",
+    "sysretq",
+    "2:",
+    "mov rax, rdi",
+    "jmp user_return",
+    user_rsp = sym USER_RSP,
+    stack_top = sym SYSCALL_STACK_TOP,
+    exit = const SYS_EXIT,
+    dispatch = sym syscall_dispatch,
+);
+
+unsafe extern "C" {
+    fn syscall_entry();
+}
+
+/// Enables `syscall`/`sysret` and points them at `syscall_entry`. Call after `gdt::init`.
+pub fn init() {
+    let (kernel_code, kernel_data) = gdt::kernel_selectors();
+    let (user_code, user_data) = gdt::user_selectors();
+    unsafe {
+        SYSCALL_STACK_TOP = (&raw const SYSCALL_STACK.0) as u64 + STACK_SIZE as u64;
+        Star::write(user_code, user_data, kernel_code, kernel_data).expect("GDT order doesn't suit sysret");
+        LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+        // run the kernel side with interrupts off and the direction flag clear
+        SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG);
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+}
+
+extern "C" fn syscall_dispatch(number: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    match SYSCALLS.iter().find(|&&(n, _)| n == number) {
+        Some((_, handler)) => handler(arg1, arg2, arg3),
+        None => ERROR,
+    }
+}
+
+/// Checks that `ptr..ptr + len` lies in pages mapped for ring 3.
+fn user_range(ptr: u64, len: u64) -> bool {
+    let Some(end) = ptr.checked_add(len) else { return false };
+    if end > 0x0000_8000_0000_0000 {
+        return false;
+    }
+    with_mapper(|mapper, _| {
+        (ptr & !0xfff..end).step_by(4096).all(|page| {
+            matches!(mapper.translate(VirtAddr::new(page)),
+                TranslateResult::Mapped { flags, .. } if flags.contains(PageTableFlags::USER_ACCESSIBLE))
+        })
+    })
+}
+
+fn sys_write(ptr: u64, len: u64, _: u64) -> u64 {
+    if !user_range(ptr, len) {
+        return ERROR;
+    }
+    let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+    match core::str::from_utf8(bytes) {
+        Ok(text) => {
+            write!(Writer, "{}", text).ok();
+            len
+        }
+        Err(_) => ERROR,
+    }
+}
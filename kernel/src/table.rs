@@ -0,0 +1,98 @@
+//! Aligned columns for commands that print tables.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use crate::screen::screenwriter;
+
+/// Widest a column gets, however long its cells are
+pub const MAX_COLUMN: usize = 32;
+/// Spaces between columns
+const GAP: usize = 2;
+
+/// Width of each column: its longest cell, at most `MAX_COLUMN`, with the last columns
+/// narrowed so that a row takes no more than `max_total` characters. A column is never
+/// narrowed to less than one character, so very narrow limits may still be exceeded.
+pub fn column_widths(rows: &[Vec<String>], max_total: usize) -> Vec<usize> {
+    let mut widths: Vec<usize> = Vec::new();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            let len = cell.chars().count().min(MAX_COLUMN);
+            match widths.get_mut(i) {
+                Some(width) => *width = (*width).max(len),
+                None => widths.push(len),
+            }
+        }
+    }
+    let mut total = widths.iter().sum::<usize>() + GAP * widths.len().saturating_sub(1);
+    for width in widths.iter_mut().rev() {
+        if total <= max_total {
+            break;
+        }
+        let narrower = width.saturating_sub(total - max_total).max(1);
+        total -= *width - narrower;
+        *width = narrower;
+    }
+    widths
+}
+
+/// Writes `rows` to `out` in columns as wide as `column_widths` makes them for the
+/// screen, or without a width limit on serial. Cells too wide for their column end in
+/// `~`; the font has no `…`.
+pub fn print(out: &mut dyn Write, rows: &[Vec<String>]) {
+    let cols = screenwriter().map_or(usize::MAX, |screen| screen.dimensions().2);
+    let widths = column_widths(rows, cols);
+    for row in rows {
+        let mut line = String::new();
+        for (i, (cell, &width)) in row.iter().zip(&widths).enumerate() {
+            if i > 0 {
+                line.push_str(&" ".repeat(GAP));
+            }
+            let len = cell.chars().count();
+            if len > width {
+                line.extend(cell.chars().take(width - 1));
+                line.push('~');
+            } else {
+                line.push_str(cell);
+                // no trailing spaces after the last cell
+                if i + 1 < row.len() {
+                    line.push_str(&" ".repeat(width - len));
+                }
+            }
+        }
+        writeln!(out, "{}", line).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn cells(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter().map(|row| row.iter().map(|&cell| String::from(cell)).collect()).collect()
+    }
+
+    #[test]
+    fn columns_fit_their_longest_cell() {
+        let rows = cells(&[&["name", "size"], &["kernel.log", "4096"], &["a", "12345678"]]);
+        assert_eq!(column_widths(&rows, usize::MAX), vec![10, 8]);
+        // ragged rows still count towards the columns they have
+        let rows = [rows, cells(&[&["", "", "third"]])].concat();
+        assert_eq!(column_widths(&rows, usize::MAX), vec![10, 8, 5]);
+        assert_eq!(column_widths(&[], 80), vec![]);
+    }
+
+    #[test]
+    fn columns_are_capped_and_narrowed_from_the_right() {
+        let long = "x".repeat(50);
+        assert_eq!(column_widths(&cells(&[&[&long]]), usize::MAX), vec![MAX_COLUMN]);
+        let rows = cells(&[&["0123456789", "0123456789", "0123456789"]]);
+        // 10 + 2 + 10 + 2 + 10 = 34 wide; the last column gives up 4
+        assert_eq!(column_widths(&rows, 30), vec![10, 10, 6]);
+        // the last is down to one character, so the middle one gives up the rest
+        assert_eq!(column_widths(&rows, 20), vec![10, 5, 1]);
+        // never below one character, even past the limit
+        assert_eq!(column_widths(&rows, 3), vec![1, 1, 1]);
+    }
+}
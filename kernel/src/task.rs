@@ -0,0 +1,160 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use crate::allocator;
+
+const STACK_SIZE: usize = 16 * 1024;
+
+/// Task id of the kernel's own thread of execution, which runs the shell
+pub const MAIN_TASK: usize = 0;
+
+pub struct Task {
+    id: usize,
+    /// Stack pointer saved by `switch_stack` while the task isn't running
+    rsp: u64,
+    /// Owned by the task until it's dropped; `None` for the main task, which runs on
+    /// the boot stack
+    _stack: Option<Box<[u8]>>,
+    /// Addresses `_stack` (or the boot stack) covers, for the panic backtrace
+    bounds: Range<u64>,
+    /// Interrupt nesting depth saved while the task isn't running, see
+    /// `allocator::swap_irq_depth`
+    irq_depth: usize,
+    finished: bool,
+}
+
+struct Scheduler {
+    // boxed so `rsp` stays put while `switch_stack` writes to it
+    #[allow(clippy::vec_box)]
+    tasks: Vec<Box<Task>>,
+    current: usize,
+    next_id: usize,
+}
+
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+// Saves the callee-saved registers on the current stack, stores the stack pointer in
+// *rdi, loads rsi as the new one and pops that task's registers. The caller-saved ones
+// are already saved by whoever called `switch_stack`, and a new task's stack is set up
+// to "return" into `task_start`, with the entry point in r12.
+global_asm!(
+    "switch_stack:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    "task_start:",
+    "sti",
+    "mov rdi, r12",
+    "call {main}",
+    main = sym task_main,
+);
+
+unsafe extern "C" {
+    fn switch_stack(old_rsp: *mut u64, new_rsp: u64);
+    fn task_start();
+}
+
+/// Makes the code running now the main task. Call once before `spawn`, and after
+/// `kernel::set_stack` has been given the boot stack.
+pub fn init() {
+    let main = Box::new(Task { id: MAIN_TASK, rsp: 0, _stack: None, bounds: kernel::stack(), irq_depth: 0, finished: false });
+    *SCHEDULER.lock() = Some(Scheduler { tasks: vec![main], current: 0, next_id: MAIN_TASK + 1 });
+}
+
+/// Starts `entry` as a new task and returns its id. It first runs at the next switch.
+pub fn spawn(entry: fn()) -> usize {
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let bottom = stack.as_mut_ptr() as u64;
+    let top = (bottom + STACK_SIZE as u64) & !0xf;
+    // what switch_stack pops: r15, r14, r13, r12 (the entry point), rbp, rbx, then
+    // the return address
+    let frame = [0, 0, 0, entry as usize as u64, 0, 0, task_start as usize as u64];
+    let rsp = top - size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; 7]).write(frame) };
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().expect("scheduler not initialized");
+        let id = scheduler.next_id;
+        scheduler.next_id += 1;
+        scheduler.tasks.push(Box::new(Task { id, rsp, _stack: Some(stack), bounds: bottom..top, irq_depth: 0, finished: false }));
+        id
+    })
+}
+
+/// Id of the running task.
+pub fn current() -> usize {
+    without_interrupts(|| SCHEDULER.lock().as_ref().map_or(MAIN_TASK, |s| s.tasks[s.current].id))
+}
+
+/// Number of tasks that haven't finished, the main task included.
+pub fn count() -> usize {
+    without_interrupts(|| SCHEDULER.lock().as_ref().map_or(1, |s| s.tasks.iter().filter(|t| !t.finished).count()))
+}
+
+/// Gives the CPU to the next task, coming back when it's this task's turn again.
+pub fn yield_now() {
+    without_interrupts(schedule);
+}
+
+/// Switches to the next task from the timer interrupt. The interrupt must already be
+/// acknowledged, or no more ticks arrive until this task runs again.
+pub fn preempt() {
+    schedule();
+}
+
+/// Round robin: switches from the current task to the next unfinished one. Runs with
+/// interrupts disabled; each task gets its own interrupt flag back when it resumes.
+fn schedule() {
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else { return };
+        let current_id = scheduler.tasks[scheduler.current].id;
+        // drop finished tasks, apart from the current one, whose stack is still in use
+        scheduler.tasks.retain(|task| !task.finished || task.id == current_id);
+        let tasks = &mut scheduler.tasks;
+        let current = tasks.iter().position(|task| task.id == current_id).unwrap();
+        // the main task never finishes, so there's always one to go to
+        let next = (1..=tasks.len()).map(|step| (current + step) % tasks.len())
+            .find(|&i| !tasks[i].finished).unwrap();
+        if next == current {
+            return;
+        }
+        scheduler.current = next;
+        kernel::set_stack(scheduler.tasks[next].bounds.clone());
+        scheduler.tasks[current].irq_depth = allocator::swap_irq_depth(scheduler.tasks[next].irq_depth);
+        (&raw mut scheduler.tasks[current].rsp, scheduler.tasks[next].rsp)
+    };
+    unsafe { switch_stack(old_rsp, new_rsp) };
+}
+
+// entered from task_start with the entry point in rdi; only Rust code ever calls it
+#[allow(improper_ctypes_definitions)]
+extern "C" fn task_main(entry: fn()) -> ! {
+    entry();
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().unwrap();
+        let current = scheduler.current;
+        scheduler.tasks[current].finished = true;
+    });
+    // never comes back now that the task is finished
+    loop {
+        yield_now();
+    }
+}
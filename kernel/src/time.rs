@@ -0,0 +1,62 @@
+//! High-resolution time from the TSC, calibrated against the PIT.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::cpuid::{self, Feature};
+use crate::interrupts::{self, CALIBRATION_DIVISOR, pit_interval};
+
+/// TSC increments per second, 0 until `init` has measured it
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+/// TSC value at `init`, where `now_ns` counts from
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+/// Whether the CPU says its TSC runs at a constant rate in every power state
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// Reads the time stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Measures the TSC rate against the PIT, so `now_ns` can use it. Without an invariant
+/// TSC, whose rate may change with the CPU's, `now_ns` stays with the timer ticks.
+pub fn init() {
+    let invariant = cpuid::has(Feature::InvariantTsc);
+    INVARIANT.store(invariant, Ordering::Relaxed);
+    let (mut start, mut end) = (0, 0);
+    x86_64::instructions::interrupts::without_interrupts(|| pit_interval(|| start = rdtsc(), || end = rdtsc()));
+    let hz = (end - start) * CALIBRATION_DIVISOR;
+    TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+    TSC_HZ.store(hz, Ordering::Relaxed);
+    if invariant {
+        crate::info!("TSC: {} Hz, invariant", hz);
+    } else {
+        crate::info!("TSC: {} Hz, but not invariant; timing falls back to timer ticks", hz);
+    }
+}
+
+/// TSC increments per second, if measured.
+pub fn tsc_hz() -> Option<u64> {
+    match TSC_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// Whether `now_ns` goes by the TSC; otherwise it only moves once per timer tick.
+pub fn is_precise() -> bool {
+    INVARIANT.load(Ordering::Relaxed) && tsc_hz().is_some()
+}
+
+/// Nanoseconds since `init`, or without a usable TSC since the timer started, in steps
+/// of a timer tick.
+pub fn now_ns() -> u64 {
+    match tsc_hz() {
+        Some(hz) if is_precise() => {
+            let cycles = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+            (u128::from(cycles) * 1_000_000_000 / u128::from(hz)) as u64
+        }
+        _ => {
+            let ticks = u128::from(interrupts::current_ticks());
+            (ticks * 1_000_000_000 / u128::from(interrupts::timer_hz().max(1))) as u64
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use kernel::paging::{map_page, unmap_page};
+use kernel::usermode;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageTableFlags, PhysFrame, Translate};
+use x86_64::VirtAddr;
+use crate::frame_allocator::with_mapper;
+use crate::memory;
+
+/// Where the test program's code and stack pages are mapped: high in the lower half,
+/// away from what the bootloader maps. `run` checks that they are free anyway.
+const USER_CODE: u64 = 0x0000_7000_0000_0000;
+const USER_STACK: u64 = USER_CODE + 0x10_0000;
+
+/// `mov eax, 42; int 0x80; jmp $`
+const TEST_PROGRAM: [u8; 9] = [0xB8, 0x2A, 0x00, 0x00, 0x00, 0xCD, 0x80, 0xEB, 0xFE];
+
+/// Runs `program` in ring 3 from a fresh user-accessible code page, with one page of
+/// stack, and returns what it left in rax when it did `int 0x80`. The pages are unmapped
+/// and freed again afterwards.
+pub fn run(program: &[u8]) -> Result<u64, &'static str> {
+    if program.len() > 4096 {
+        return Err("program larger than a page");
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let (code, stack) = (VirtAddr::new(USER_CODE), VirtAddr::new(USER_STACK));
+    let frames: Result<(PhysFrame, PhysFrame), &'static str> = with_mapper(|mapper, frames| {
+        if mapper.translate_addr(code).is_some() || mapper.translate_addr(stack).is_some() {
+            return Err("user pages already in use");
+        }
+        let code_frame = frames.allocate_frame().ok_or("out of frames")?;
+        let Some(stack_frame) = frames.allocate_frame() else {
+            unsafe { frames.deallocate_frame(code_frame) };
+            return Err("out of frames");
+        };
+        let mapped = map_page(mapper, frames, code, code_frame.start_address(), flags)
+            .and_then(|_| map_page(mapper, frames, stack, stack_frame.start_address(), flags));
+        if mapped.is_err() {
+            unmap_page(mapper, code).ok();
+            unsafe {
+                frames.deallocate_frame(code_frame);
+                frames.deallocate_frame(stack_frame);
+            }
+            return Err("mapping failed");
+        }
+        Ok((code_frame, stack_frame))
+    });
+    let (code_frame, stack_frame) = frames?;
+
+    // copy through the physical memory mapping, which the kernel can always write
+    let target = memory::phys_to_virt(code_frame.start_address().as_u64(), 4096).ok_or("frame outside the memory map")?;
+    unsafe {
+        core::ptr::write_bytes(target, 0xCC, 4096); // int3 after the program
+        core::ptr::copy_nonoverlapping(program.as_ptr(), target, program.len());
+    }
+
+    let result = unsafe { usermode::enter_user(code, stack + 4096u64) };
+
+    with_mapper(|mapper, frames| {
+        unmap_page(mapper, code).ok();
+        unmap_page(mapper, stack).ok();
+        unsafe {
+            frames.deallocate_frame(code_frame);
+            frames.deallocate_frame(stack_frame);
+        }
+    });
+    Ok(result)
+}
+
+/// Runs a program that just returns 42, to check the way into ring 3 and back.
+pub fn test() -> Result<u64, &'static str> {
+    run(&TEST_PROGRAM)
+}
+
+/// Line the `syscall_test` program prints
+pub const SYSCALL_MESSAGE: &[u8] = b"hello from ring 3\n";
+/// Code the `syscall_test` program exits with
+pub const SYSCALL_EXIT_CODE: u64 = 7;
+
+/// `lea rdi, [rip + message]; mov esi, len; xor eax, eax (write); syscall;
+/// mov edi, code; mov eax, 1 (exit); syscall; jmp $`, followed by the message
+const SYSCALL_PROGRAM: [u8; 30] = [
+    0x48, 0x8D, 0x3D, 0x17, 0x00, 0x00, 0x00,
+    0xBE, SYSCALL_MESSAGE.len() as u8, 0x00, 0x00, 0x00,
+    0x31, 0xC0,
+    0x0F, 0x05,
+    0xBF, SYSCALL_EXIT_CODE as u8, 0x00, 0x00, 0x00,
+    0xB8, 0x01, 0x00, 0x00, 0x00,
+    0x0F, 0x05,
+    0xEB, 0xFE,
+];
+
+/// Runs a program that prints `SYSCALL_MESSAGE` with the `write` system call, then
+/// calls `exit` with `SYSCALL_EXIT_CODE`.
+pub fn syscall_test() -> Result<u64, &'static str> {
+    let mut program = [0; SYSCALL_PROGRAM.len() + SYSCALL_MESSAGE.len()];
+    program[..SYSCALL_PROGRAM.len()].copy_from_slice(&SYSCALL_PROGRAM);
+    program[SYSCALL_PROGRAM.len()..].copy_from_slice(SYSCALL_MESSAGE);
+    run(&program)
+}
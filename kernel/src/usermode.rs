@@ -0,0 +1,72 @@
+use core::arch::global_asm;
+use x86_64::instructions::segmentation::{Segment, DS, ES, SS};
+use x86_64::VirtAddr;
+use crate::gdt;
+
+/// Interrupt vector ring 3 code uses to return to the kernel
+pub const USER_RETURN_VECTOR: u8 = 0x80;
+
+/// Kernel stack pointer saved by `enter_user`, restored by `user_return`
+static mut KERNEL_RSP: u64 = 0;
+
+unsafe extern "C" {
+    fn enter_user_asm(rip: u64, rsp: u64, cs: u64, ss: u64) -> u64;
+    fn user_return();
+}
+
+// enter_user_asm saves the callee-saved registers and the stack pointer, then irets to
+// ring 3 with interrupts disabled. user_return is the handler for `int 0x80`; it drops
+// the interrupt frame by going back to the saved stack and returns from enter_user_asm
+// with the rax the user code left.
+global_asm!(
+    ".global enter_user_asm",
+    "enter_user_asm:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rip + {rsp}], rsp",
+    "push rcx",       // ss
+    "push rsi",       // rsp
+    "push 0x2",       // rflags: only the reserved bit
+    "push rdx",       // cs
+    "push rdi",       // rip
+    "iretq",
+    ".global user_return",
+    "user_return:",
+    "mov rsp, [rip + {rsp}]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    rsp = sym KERNEL_RSP,
+);
+
+/// Address of the handler to install for `USER_RETURN_VECTOR`.
+pub(crate) fn user_return_addr() -> VirtAddr {
+    VirtAddr::new(user_return as usize as u64)
+}
+
+/// Runs the code at `rip` in ring 3 with the stack pointer `rsp` until it executes
+/// `int 0x80`, and returns the value of its rax at that point. Both addresses must be
+/// in pages mapped user-accessible. Interrupts stay disabled in ring 3.
+///
+/// ## Safety
+/// The user code must reach `int 0x80`; a fault in ring 3 is handled like a kernel fault.
+pub unsafe fn enter_user(rip: VirtAddr, rsp: VirtAddr) -> u64 {
+    let (code, data) = gdt::user_selectors();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let result = enter_user_asm(rip.as_u64(), rsp.as_u64(), u64::from(code.0), u64::from(data.0));
+        // coming back through an interrupt gate left SS null; put the kernel's back
+        let (_, kernel_data) = gdt::kernel_selectors();
+        SS::set_reg(kernel_data);
+        DS::set_reg(kernel_data);
+        ES::set_reg(kernel_data);
+        result
+    })
+}